@@ -0,0 +1,56 @@
+// Turns what the server tells us about fights, and our travels, into lines for the combat log.
+
+use encosmo_shared::{server_components::GameObjectDetails, AttackReport, TravelStop};
+use specs::{World, WorldExt};
+
+use crate::{components::PlayerInput, resources::ServerEntities};
+
+pub fn describe_attack(world: &World, report: &AttackReport) -> String {
+    let (attacker, ours) = name(world, report.attacker);
+    let (target, _) = name(world, report.target);
+    match (report.ranged, report.hit, ours) {
+        (false, true, true) => format!("You hit {}", target),
+        (false, true, false) => format!("{} hits {}", attacker, target),
+        (false, false, true) => format!("You miss {}", target),
+        (false, false, false) => format!("{} misses {}", attacker, target),
+        (true, true, true) => format!("You shoot {}", target),
+        (true, true, false) => format!("{} shoots {}", attacker, target),
+        (true, false, true) => format!("You shoot at {} and miss", target),
+        (true, false, false) => format!("{} shoots at {} and misses", attacker, target)
+    }
+}
+
+pub fn describe_damage(world: &World, eid: u32, amount: i32) -> String {
+    match name(world, eid) {
+        (_, true) => format!("You take {} damage", amount),
+        (target, false) => format!("{} takes {} damage", target, amount)
+    }
+}
+
+pub fn describe_death(world: &World, eid: u32) -> String {
+    match name(world, eid) {
+        (_, true) => "You have been downed".to_owned(),
+        (target, false) => format!("{} dies", target)
+    }
+}
+
+pub fn describe_travel_stop(world: &World, reason: &TravelStop) -> String {
+    match reason {
+        TravelStop::Arrived => "You arrive".to_owned(),
+        TravelStop::Explored => "There's nowhere left to explore".to_owned(),
+        TravelStop::Blocked => "Something is in the way".to_owned(),
+        TravelStop::Interrupted(eid) => format!("You stop, having spotted {}", name(world, *eid).0)
+    }
+}
+
+/// What to call the entity, and whether it's our own player.
+fn name(world: &World, eid: u32) -> (String, bool) {
+    let Some (entity) = world.read_resource::<ServerEntities>().0.get(&eid).copied() else {
+        return ("something".to_owned(), false);
+    };
+    if world.read_storage::<PlayerInput>().contains(entity) {
+        return ("you".to_owned(), true);
+    }
+    let name = world.read_storage::<GameObjectDetails>().get(entity).map_or_else(|| "something".to_owned(), |details| details.name.clone());
+    (name, false)
+}
@@ -1,6 +1,9 @@
 // Any component in this file is a client-component, meaning it's
 // specific to client-side operation and should never been sent to the server.
 
+use std::collections::VecDeque;
+
+use encosmo_shared::server_components::Position;
 use macroquad::prelude::*;
 use specs::prelude::*;
 
@@ -9,10 +12,30 @@ impl Component for PlayerInput {
     type Storage = VecStorage<Self>;
 }
 
-pub struct Render {
+/// A region of a texture, as named in `content/sprites.json`.
+#[derive(Clone)]
+pub struct Sprite {
     pub texture: Texture2D,
     pub source: Rect
 }
+
+/// Layers are drawn in the order they're declared, bottom first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenderLayer {
+    Floor,      // the map, and anything lying flat on it
+    Items,
+    Actors,
+    Overlay     // drawn over everything, e.g. cursors and markers
+}
+
+impl RenderLayer {
+    pub const ALL: [RenderLayer; 4] = [RenderLayer::Floor, RenderLayer::Items, RenderLayer::Actors, RenderLayer::Overlay];
+}
+
+pub struct Render {
+    pub sprite: Sprite,
+    pub layer: RenderLayer
+}
 impl Component for Render {
     type Storage = VecStorage<Self>;
 }
@@ -27,4 +50,18 @@ impl Component for FollowCamera {
 pub struct ServerEntityId(pub u32);
 impl Component for ServerEntityId {
     type Storage = VecStorage<Self>;
+}
+
+/// Positions the server has sent for a remote entity, with the time we received them, oldest first.
+#[derive(Default)]
+pub struct PositionHistory(pub VecDeque<(f64, Position)>);
+impl Component for PositionHistory {
+    type Storage = VecStorage<Self>;
+}
+
+/// Where an entity is drawn in tiles, which may be in between two tiles while it's interpolated.
+/// Entities without one are drawn at their `Position`.
+pub struct RenderPosition(pub Vec2);
+impl Component for RenderPosition {
+    type Storage = VecStorage<Self>;
 }
\ No newline at end of file
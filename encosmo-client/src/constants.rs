@@ -1,5 +1,32 @@
-use macroquad::miniquad::conf::Icon;
+use macroquad::{color::Color, miniquad::conf::Icon};
 
+/// Size of one tile on screen in pixels. Positions from the server are in tiles.
+pub const TILE_SIZE: f32 = 16.;
+
+/// Floor tiles are drawn as plain squares, brighter where our player can see.
+pub const VISIBLE_FLOOR_COLOR: Color = Color::new(0.16, 0.16, 0.2, 1.);
+pub const PARTY_FLOOR_COLOR: Color = Color::new(0.1, 0.1, 0.14, 1.);
+pub const EXPLORED_FLOOR_COLOR: Color = Color::new(0.06, 0.06, 0.08, 1.);
+
+/// Tints for tiles only the rest of the party can see, and for remembered tiles that aren't in view.
+pub const PARTY_TINT: Color = Color::new(0.65, 0.65, 0.75, 1.);
+pub const FOG_TINT: Color = Color::new(0.35, 0.35, 0.45, 1.);
+
+/// Tint for players that have been downed.
+pub const DOWNED_TINT: Color = Color::new(0.6, 0.2, 0.2, 0.8);
+
+/// How long damage numbers float above whoever was hit, in seconds.
+pub const DAMAGE_NUMBER_DURATION: f64 = 1.;
+
+/// How many lines of the combat log are shown.
+pub const COMBAT_LOG_LINES: usize = 6;
+
+/// How long a move takes on the server at normal speed and the default tick rate, in seconds.
+pub const MOVE_INTERVAL: f64 = 0.5;
+
+/// How far in the past remote entities are drawn, in seconds. Needs to be at least `MOVE_INTERVAL`
+/// so there's always a newer position to move towards.
+pub const DEFAULT_INTERPOLATION_DELAY: f64 = 0.6;
 
 
 pub fn icon() -> Icon {
@@ -1,24 +1,21 @@
 use crate::components::*;
-use encosmo_shared::server_components::{Position, Translate};
+use anyhow::Result;
+use encosmo_shared::server_components::Translate;
 use macroquad::prelude::*;
-use specs::{Builder, Entity, World, WorldExt};
+use specs::{Entity, World, WorldExt};
 
-pub fn create_player(world: &mut World, game_texture: &Texture2D, eid: u32) -> Entity {
-    world
-        .create_entity()
-        .with(Translate::default())
-        .with(Position::default())
-        .with(PlayerInput)
-        .with(Render {
-            texture: game_texture.clone(),
-            source: Rect::new(432., 48., 16., 16.),
-        })
-        .with(FollowCamera {
-            camera: Camera2D {
-                zoom: (4. / screen_width(), 4. / screen_height()).into(),
-                ..Default::default()
-            }
-        })
-        .with(ServerEntityId(eid))
-        .build()
-}
\ No newline at end of file
+/// Turns the mirror of our own player entity into something we control and follow with the camera.
+pub fn make_player(world: &mut World, entity: Entity) -> Result<()> {
+    // we may have seen ourselves before knowing it was us, but we're drawn where we predict, not interpolated
+    world.write_storage::<PositionHistory>().remove(entity);
+    world.write_storage::<RenderPosition>().remove(entity);
+    world.write_storage().insert(entity, Translate::default())?;
+    world.write_storage().insert(entity, PlayerInput)?;
+    world.write_storage().insert(entity, FollowCamera {
+        camera: Camera2D {
+            zoom: (4. / screen_width(), 4. / screen_height()).into(),
+            ..Default::default()
+        }
+    })?;
+    Ok (())
+}
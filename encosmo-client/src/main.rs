@@ -1,11 +1,15 @@
-use std::{io::{Read, Write}, net::TcpStream, sync::mpsc, thread::spawn};
+use std::{env, io::{Read, Write}, net::TcpStream, sync::mpsc, thread::spawn};
 
-use anyhow::Result;
-use entities::create_player;
+use anyhow::{bail, Result};
+use entities::*;
 use components::*;
-use encosmo_shared::{server_components::{Position, Translate}, Packet};
+use encosmo_shared::{codec::{decode_packet, encode_packet, FrameDecoder, WireFormat}, handshake::{Capabilities, HANDSHAKE_TIMEOUT, PROTOCOL_VERSION}, server_components::*, Packet};
 use macroquad::prelude::*;
-use resources::ConnectionId;
+use constants::DEFAULT_INTERPOLATION_DELAY;
+use prediction::reconcile;
+use combat_log::*;
+use replication::*;
+use resources::*;
 use specs::{DispatcherBuilder, World, WorldExt};
 use systems::*;
 
@@ -14,6 +18,9 @@ mod components;
 mod systems;
 mod constants;
 mod resources;
+mod replication;
+mod prediction;
+mod combat_log;
 
 
 fn window_conf() -> Conf {
@@ -30,39 +37,89 @@ fn window_conf() -> Conf {
 async fn main() -> Result<()> {
 
     // load content
-    let game_texture = load_texture("content/art/game-tiles.png").await?;
-    game_texture.set_filter(FilterMode::Nearest);
+    let atlas = SpriteAtlas::load("content/sprites.json").await?;
+
+    // binary unless asked for json, which is easier to inspect when debugging
+    let wire_format = if env::args().any(|arg| arg == "--json") {
+        WireFormat::Json
+    } else {
+        WireFormat::Binary
+    };
+
+    // e.g. `--interp-delay 300` draws remote entities 300ms in the past
+    let interpolation_delay = match env::args().skip_while(|arg| arg != "--interp-delay").nth(1) {
+        Some (ms) => ms.parse::<f64>()? / 1000.,
+        None => DEFAULT_INTERPOLATION_DELAY
+    };
 
     let mut stream = TcpStream::connect(("127.0.0.1", 42523))?;
     let stream_cpy = stream.try_clone()?;
 
+    let mut decoder = FrameDecoder::new();
+    let capabilities = match handshake(&mut stream, &mut decoder) {
+        Ok (capabilities) => capabilities,
+        Err (e) => return show_disconnected(&e.to_string()).await
+    };
+
+    // every connection starts out as json, so announce the switch before anything else is sent.
+    // fall back to json if the server doesn't support anything else
+    let wire_format = if capabilities.contains(Capabilities::BINARY_WIRE_FORMAT) {
+        wire_format
+    } else {
+        WireFormat::Json
+    };
+    if wire_format != WireFormat::Json {
+        send_packet(&mut stream, Packet::SetWireFormat(wire_format), WireFormat::Json)?;
+    }
+
     // internal packet queue (enqueues from systems)
     let (packet_tx, packet_rx) = mpsc::channel::<Packet>();
 
     // set up connection to server
     let (server_tx, server_rx) = mpsc::channel::<Packet>();
-    let handle = spawn(move || recv_packet_loop(stream_cpy, server_tx));
+    let handle = spawn(move || recv_packet_loop(stream_cpy, decoder, server_tx));
 
     // set up ECS
     let mut world = World::new();
     world.register::<Position>();
     world.register::<Translate>();
+    world.register::<GameObjectDetails>();
+    world.register::<PlayerDetails>();
+    world.register::<Health>();
+    world.register::<Defence>();
+    world.register::<Item>();
     world.register::<PlayerInput>();
     world.register::<Render>();
     world.register::<FollowCamera>();
     world.register::<ServerEntityId>();
+    world.register::<PositionHistory>();
+    world.register::<RenderPosition>();
 
     // adding resources
     world.insert(ConnectionId::default());
+    world.insert(ServerEntities::default());
+    world.insert(PendingInputs::default());
+    world.insert(Fog::default());
+    world.insert(Party::default());
+    world.insert(DamageNumbers::default());
+    world.insert(Target::default());
+    world.insert(Inventory::default());
+    world.insert(CombatLog::default());
+    world.insert(InterpolationDelay(interpolation_delay));
+    world.insert(atlas);
 
     // with_thread_local means the systems are run sequentually, so order matters
     let mut dispatcher = DispatcherBuilder::new()
+        .with_thread_local(TargetingSystem)
         .with_thread_local(InputSystem {
             packet_tx: packet_tx.clone()
         })
         .with_thread_local(MoveSystem)
         .with_thread_local(FollowCameraSystem)  // e.g. have camera follow run AFTER move system for late-update
+        .with_thread_local(InterpolationSystem)
+
         .with_thread_local(RenderSystem)
+        .with_thread_local(HudSystem)
         .build();
 
 
@@ -80,16 +137,19 @@ async fn main() -> Result<()> {
 
         // read packets
         while let Ok (packet) = server_rx.try_recv() {
-            process_packet(packet.clone(), &mut world, &game_texture)?;
+            if let Packet::Disconnect(reason) = packet {
+                return show_disconnected(&reason).await;
+            }
+            process_packet(packet, &mut world, &packet_tx)?;
         }
 
         // Run systems
-        dispatcher.dispatch(&mut world);
+        dispatcher.dispatch(&world);
         world.maintain();
 
         // send any outgoing packets
         while let Ok (packet) = packet_rx.try_recv() {
-            send_packet(&mut stream, packet)?;
+            send_packet(&mut stream, packet, wire_format)?;
         }
 
         draw_text("text", 32., 32., 32., YELLOW);
@@ -99,32 +159,76 @@ async fn main() -> Result<()> {
     }
 }
 
-fn recv_packet_loop(mut stream: TcpStream, tx: mpsc::Sender<Packet>) -> Result<()> {
+/// Says `Hello` and waits for the server to welcome us, returning the capabilities both sides agreed on.
+/// The handshake is always spoken in json so a mismatched server can still tell us why it rejected us.
+fn handshake(stream: &mut TcpStream, decoder: &mut FrameDecoder) -> Result<Capabilities> {
+    send_packet(stream, Packet::Hello(PROTOCOL_VERSION, Capabilities::supported()), WireFormat::Json)?;
+
+    stream.set_read_timeout(Some (HANDSHAKE_TIMEOUT))?;
+    let frame = recv_frame(stream, decoder);
+    stream.set_read_timeout(None)?;
+
+    let Some (frame) = frame? else {
+        bail!("Server closed the connection during the handshake");
+    };
+    match decode_packet(&frame, WireFormat::Json) {
+        Ok (Packet::Welcome(_, capabilities)) => Ok (capabilities),
+        Ok (Packet::Disconnect(reason)) => bail!(reason),
+        Ok (p) => bail!("Expected Welcome from the server but got {:?}", p),
+        Err (e) => bail!("Could not understand the server's handshake, it is likely incompatible with this client ({})", e)
+    }
+}
+
+/// Shown instead of the game once the server has turned us away, until the player closes it.
+async fn show_disconnected(reason: &str) -> Result<()> {
+    eprintln!("Disconnected from server: {}", reason);
     loop {
-        let mut buf = [0u8; 1024];
-        let read = stream.read(&mut buf)?;
-        if read == 0 {
-            // connection closed
+        clear_background(BLACK);
+        draw_text("Disconnected from server", 32., 64., 32., RED);
+        draw_text(reason, 32., 104., 20., WHITE);
+        draw_text("Press Escape to quit", 32., 144., 20., GRAY);
+        if is_key_pressed(KeyCode::Escape) {
             return Ok (());
         }
+        next_frame().await
+    }
+}
 
-        let res = String::from_utf8(buf[0..read].into());
-        if let Err (e) = res {
-            eprintln!("Error decoding buffer into string: {}", e);
-            continue;
+/// Reads until a whole frame is available. `None` means the connection was closed.
+fn recv_frame(stream: &mut TcpStream, decoder: &mut FrameDecoder) -> Result<Option<Vec<u8>>> {
+    let mut buf = [0u8; 4096];
+    loop {
+        if let Some (frame) = decoder.next_frame()? {
+            return Ok (Some (frame));
         }
-        let str = res.unwrap();
-        let res = serde_json::from_str::<Packet>(&str);
-        if let Err (e) = res {
-            eprintln!("Error converting string into packet: {}", e);
-            continue;
+        let read = stream.read(&mut buf)?;
+        if read == 0 {
+            return Ok (None);
+        }
+        decoder.extend(&buf[0..read]);
+    }
+}
+
+fn recv_packet_loop(mut stream: TcpStream, mut decoder: FrameDecoder, tx: mpsc::Sender<Packet>) -> Result<()> {
+    let mut format = WireFormat::default();
+
+    // a single read may contain any number of frames, or only part of one
+    while let Some (frame) = recv_frame(&mut stream, &mut decoder)? {
+        match decode_packet(&frame, format) {
+            Err (e) => eprintln!("Error converting frame into packet: {}", e),
+            Ok (Packet::SetWireFormat(f)) => {
+                // server has acknowledged our format, every frame after this one uses it
+                format = f;
+            },
+            Ok (packet) => tx.send(packet)?
         }
-        let packet = res.unwrap();
-        tx.send(packet)?;
     }
+
+    // connection closed
+    Ok (())
 }
 
-fn process_packet(p: Packet, world: &mut World, game_texture: &Texture2D) -> Result<()> {
+fn process_packet(p: Packet, world: &mut World, packet_tx: &mpsc::Sender<Packet>) -> Result<()> {
     match p {
         Packet::Id(_id) => {
             let mut connection_id = world.write_resource::<ConnectionId>();
@@ -134,23 +238,66 @@ fn process_packet(p: Packet, world: &mut World, game_texture: &Texture2D) -> Res
         Packet::PlayerConnected(id) => println!("A new player has connected: {}", id),
         Packet::PlayerDisconnected(id) => println!("Player has disconnected: {}", id),
         Packet::Name(id, name) => println!("Player with id {} has set their name to {}", id, name),
-        Packet::UpdateComponent(eid, kind) => {
-            println!("Updating component {:?} for entity with id: {}", kind, eid);
-        },
+        Packet::UpdateComponent(eid, kind) => update_component(world, eid, kind)?,
         Packet::PlayerEntityId(id, eid) => {
             let my_id = world.read_resource::<ConnectionId>().0;
             if id == my_id {
-                create_player(world, game_texture, eid);
+                let entity = mirror_entity(world, eid);
+                make_player(world, entity)?;
             }
-        }
+        },
+        Packet::Snapshot(snapshot) => {
+            let seq = snapshot.seq;
+            apply_snapshot(world, snapshot)?;
+            packet_tx.send(Packet::SnapshotAck(seq))?;
+        },
+        Packet::InputAck(seq, pos) => reconcile(world, seq, pos),
+        Packet::FloorMap(map) => {
+            println!("Arrived on floor {}", map.floor + 1);
+            world.insert(FloorMap(map));
+            // nothing is in view until the server says so
+            let mut fog = world.write_resource::<Fog>();
+            fog.visible.clear();
+            fog.shared.clear();
+        },
+        Packet::Visibility(floor, own, shared) => world.write_resource::<Fog>().update(floor, own, shared),
+        Packet::Damaged(eid, amount) => {
+            let Some (entity) = world.read_resource::<ServerEntities>().0.get(&eid).copied() else {
+                return Ok (());
+            };
+            let p = match (world.read_storage::<RenderPosition>().get(entity), world.read_storage::<Position>().get(entity)) {
+                (Some (render_pos), _) => render_pos.0,
+                (None, Some (pos)) => vec2(pos.x as f32, pos.y as f32),
+                (None, None) => return Ok (())
+            };
+            world.write_resource::<DamageNumbers>().0.push((get_time(), p, amount));
+            let line = describe_damage(world, eid, amount);
+            world.write_resource::<CombatLog>().push(line);
+        },
+        Packet::Died(eid) => {
+            let line = describe_death(world, eid);
+            world.write_resource::<CombatLog>().push(line);
+        },
+        Packet::Attack(report) => {
+            let line = describe_attack(world, &report);
+            world.write_resource::<CombatLog>().push(line);
+        },
+        Packet::TravelStopped(reason) => {
+            let line = describe_travel_stop(world, &reason);
+            world.write_resource::<CombatLog>().push(line);
+        },
+        Packet::Inventory(items) => world.insert(Inventory(items)),
+        Packet::PartyMembers(members) => {
+            println!("Party: {:?}", members);
+            world.insert(Party { members });
+        },
         p => println!("Received unhandled packet: {:?}", p)
     }
     Ok (())
 }
 
-fn send_packet(stream: &mut TcpStream, p: Packet) -> Result<()> {
-    let str = serde_json::to_string(&p)?;
-    let bs = str.as_bytes();
-    stream.write(&bs)?;
+fn send_packet(stream: &mut TcpStream, p: Packet, format: WireFormat) -> Result<()> {
+    let bytes = encode_packet(&p, format)?;
+    stream.write_all(&bytes)?;
     Ok (())
 }
\ No newline at end of file
@@ -0,0 +1,26 @@
+// Client-side prediction: our own movement is applied as soon as the key is pressed,
+// and corrected whenever the server tells us how far it has actually got.
+
+use encosmo_shared::server_components::*;
+use specs::prelude::*;
+
+use crate::{components::PlayerInput, resources::PendingInputs};
+
+/// Snaps our player to where the server says it was after input `seq`,
+/// then replays every input the server hasn't got to yet on top of that.
+pub fn reconcile(world: &mut World, seq: u32, server_pos: Position) {
+    let mut pending = world.write_resource::<PendingInputs>();
+    while pending.inputs.front().is_some_and(|(s, _)| *s <= seq) {
+        pending.inputs.pop_front();
+    }
+
+    let mut positions = world.write_storage::<Position>();
+    let players = world.read_storage::<PlayerInput>();
+    for (pos, _) in (&mut positions, &players).join() {
+        *pos = server_pos.clone();
+        for (_, t) in pending.inputs.iter() {
+            pos.x += t.dx;
+            pos.y += t.dy;
+        }
+    }
+}
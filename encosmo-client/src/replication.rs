@@ -0,0 +1,129 @@
+// Keeps our local mirrors of server entities in sync with what the server tells us.
+
+use anyhow::Result;
+use std::collections::HashSet;
+
+use encosmo_shared::{server_components::*, Snapshot};
+use macroquad::time::get_time;
+use specs::prelude::*;
+
+use crate::{components::*, constants::MOVE_INTERVAL, resources::{PendingInputs, ServerEntities, SpriteAtlas}};
+
+/// Local mirror of the server entity, created on first sight.
+pub fn mirror_entity(world: &mut World, eid: u32) -> Entity {
+    if let Some (entity) = world.read_resource::<ServerEntities>().0.get(&eid) {
+        return *entity;
+    }
+    let entity = world
+        .create_entity()
+        .with(ServerEntityId(eid))
+        .build();
+    world.write_resource::<ServerEntities>().0.insert(eid, entity);
+    entity
+}
+
+/// Creates or updates the mirror of the server entity with the given components.
+pub fn upsert_entity(world: &mut World, eid: u32, components: Vec<ServerComponentKind>) -> Result<()> {
+    let entity = mirror_entity(world, eid);
+    for component in components {
+        apply_component(world, entity, component)?;
+    }
+    update_render(world, entity)
+}
+
+/// Brings our mirrors up to date with a snapshot. A full one replaces everything we had.
+pub fn apply_snapshot(world: &mut World, snapshot: Snapshot) -> Result<()> {
+    let mut removed = snapshot.removed;
+    if snapshot.baseline.is_none() {
+        let current: HashSet<u32> = snapshot.entities.iter().map(|(eid, _)| *eid).collect();
+        removed.extend(world.read_resource::<ServerEntities>().0.keys().filter(|eid| !current.contains(eid)));
+    }
+
+    for eid in removed {
+        remove_entity(world, eid)?;
+    }
+    for (eid, components) in snapshot.entities {
+        upsert_entity(world, eid, components)?;
+    }
+    Ok (())
+}
+
+pub fn update_component(world: &mut World, eid: u32, component: ServerComponentKind) -> Result<()> {
+    upsert_entity(world, eid, vec![component])
+}
+
+pub fn remove_entity(world: &mut World, eid: u32) -> Result<()> {
+    let entity = world.write_resource::<ServerEntities>().0.remove(&eid);
+    if let Some (entity) = entity {
+        world.delete_entity(entity)?;
+    }
+    Ok (())
+}
+
+fn apply_component(world: &mut World, entity: Entity, component: ServerComponentKind) -> Result<()> {
+    match component {
+        ServerComponentKind::Position(c) => {
+            // while we're predicting our own movement, only `InputAck`s may move us
+            let predicting = world.read_storage::<PlayerInput>().contains(entity)
+                && !world.read_resource::<PendingInputs>().inputs.is_empty();
+            if predicting {
+                return Ok (());
+            }
+
+            // our own player is drawn where we predict it to be, everyone else is interpolated
+            if !world.read_storage::<PlayerInput>().contains(entity) {
+                let now = get_time();
+                let mut history = world.write_storage::<PositionHistory>();
+                let samples = &mut history.entry(entity)?.or_insert_with(PositionHistory::default).0;
+                // after standing still the last position is old, and gliding from when it arrived would cover
+                // most of the way at once, so the move is taken to have started no more than one move ago
+                if let Some ((t, _)) = samples.back_mut() {
+                    *t = t.max(now - MOVE_INTERVAL);
+                }
+                samples.push_back((now, c.clone()));
+            }
+            world.write_storage().insert(entity, c)?;
+        },
+        ServerComponentKind::Translate(c) => {
+            // a one-off nudge, kept out of storage so `MoveSystem` doesn't reapply it every frame
+            let mut positions = world.write_storage::<Position>();
+            if let Some (pos) = positions.get_mut(entity) {
+                pos.x += c.dx;
+                pos.y += c.dy;
+            }
+        },
+        ServerComponentKind::GameObjectDetails(c) => { world.write_storage().insert(entity, c)?; },
+        ServerComponentKind::PlayerDetails(c) => { world.write_storage().insert(entity, c)?; },
+        ServerComponentKind::Health(c) => { world.write_storage().insert(entity, c)?; },
+        ServerComponentKind::Defence(c) => { world.write_storage().insert(entity, c)?; },
+        ServerComponentKind::Item(c) => { world.write_storage().insert(entity, c)?; }
+    }
+    Ok (())
+}
+
+/// Picks the sprite for a mirrored entity from what we know about it so far. Anything with health is an actor.
+fn sprite_name(world: &World, entity: Entity) -> (String, RenderLayer) {
+    let layer = if world.read_storage::<Health>().contains(entity) { RenderLayer::Actors } else { RenderLayer::Items };
+    if world.read_storage::<PlayerDetails>().contains(entity) {
+        ("player".to_owned(), RenderLayer::Actors)
+    } else if let Some (details) = world.read_storage::<GameObjectDetails>().get(entity) {
+        (details.sprite.clone(), layer)
+    } else {
+        // not enough known about it yet to say what it is
+        ("look icon".to_owned(), layer)
+    }
+}
+
+/// Entities are only drawn once they have a position, until then there's nowhere to draw them.
+fn update_render(world: &mut World, entity: Entity) -> Result<()> {
+    if !world.read_storage::<Position>().contains(entity) {
+        return Ok (());
+    }
+    let (name, layer) = sprite_name(world, entity);
+    let sprite = world.read_resource::<SpriteAtlas>().sprite(&name).cloned();
+    match sprite {
+        Some (sprite) => { world.write_storage().insert(entity, Render { sprite, layer })?; },
+        None => eprintln!("Sprite atlas has no sprite named '{}'", name)
+    }
+    Ok (())
+}
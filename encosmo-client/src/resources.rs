@@ -1,5 +1,137 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use anyhow::Result;
+use encosmo_shared::{map::Map, server_components::{GameObjectDetails, Item, Translate}};
+use macroquad::prelude::*;
+use serde::Deserialize;
+use specs::Entity;
 use uuid::Uuid;
 
+use crate::{components::Sprite, constants::{COMBAT_LOG_LINES, DEFAULT_INTERPOLATION_DELAY}};
+
 
 #[derive(Default)]
 pub struct ConnectionId(pub Uuid);
+
+/// Inputs sent to the server that it hasn't acknowledged yet, oldest first.
+/// Moves have already been applied locally, so they're replayed on top of whatever the server says.
+/// Anything we can't predict, like climbing a ladder or waiting, is kept as a zero `Translate` so it's still acknowledged in order.
+#[derive(Default)]
+pub struct PendingInputs {
+    pub next_seq: u32,
+    pub inputs: VecDeque<(u32, Translate)>
+}
+
+/// How far in the past remote entities are drawn, in seconds. See `InterpolationSystem`.
+pub struct InterpolationDelay(pub f64);
+
+impl Default for InterpolationDelay {
+    fn default() -> Self {
+        InterpolationDelay(DEFAULT_INTERPOLATION_DELAY)
+    }
+}
+
+/// Layout of the floor our player is on, as last sent by the server. Absent until the first one arrives.
+pub struct FloorMap(pub Map);
+
+/// What our player can see right now, what the rest of the party can see that they can't,
+/// and what they've seen before on each floor. Tiles that have never been seen aren't drawn at all.
+#[derive(Default)]
+pub struct Fog {
+    pub visible: HashSet<(i32, i32)>,
+    pub shared: HashSet<(i32, i32)>,
+    pub explored: HashMap<u32, HashSet<(i32, i32)>>
+}
+
+impl Fog {
+    pub fn update(&mut self, floor: u32, visible: Vec<(i32, i32)>, shared: Vec<(i32, i32)>) {
+        self.visible = visible.into_iter().collect();
+        self.shared = shared.into_iter().collect();
+        let explored = self.explored.entry(floor).or_default();
+        explored.extend(&self.visible);
+        explored.extend(&self.shared);
+    }
+}
+
+/// Everyone in the party, empty if we aren't in it.
+#[derive(Default)]
+pub struct Party {
+    pub members: Vec<Uuid>
+}
+
+/// Damage recently dealt, as (time it was dealt, where in tiles, amount), drawn floating above where it happened.
+#[derive(Default)]
+pub struct DamageNumbers(pub Vec<(f64, Vec2, i32)>);
+
+/// Every item our player is carrying, as (server id, details, pile), as last told by the server.
+/// The last one is the one dropped next.
+#[derive(Default)]
+pub struct Inventory(pub Vec<(u32, GameObjectDetails, Item)>);
+
+/// Whoever we'll shoot at, picked with tab. See `TargetingSystem`.
+#[derive(Default)]
+pub struct Target(pub Option<Entity>);
+
+/// The latest goings on in fights we can see, oldest first.
+#[derive(Default)]
+pub struct CombatLog(pub VecDeque<String>);
+
+impl CombatLog {
+    pub fn push(&mut self, line: String) {
+        self.0.push_back(line);
+        while self.0.len() > COMBAT_LOG_LINES {
+            self.0.pop_front();
+        }
+    }
+}
+
+/// Maps the id the server uses for an entity to our local mirror of it.
+#[derive(Default)]
+pub struct ServerEntities(pub HashMap<u32, Entity>);
+
+/// One entry of `content/sprites.json`.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SpriteDefinition {
+    name: String,
+    texture_name: String,
+    x: f32,
+    y: f32,
+    width: f32,
+    height: f32
+}
+
+/// Every named sprite from `content/sprites.json`, ready to be drawn.
+pub struct SpriteAtlas {
+    sprites: HashMap<String, Sprite>
+}
+
+impl SpriteAtlas {
+    pub async fn load(path: &str) -> Result<Self> {
+        let definitions: Vec<SpriteDefinition> = serde_json::from_str(&load_string(path).await?)?;
+
+        // each texture is only loaded once no matter how many sprites share it
+        let mut textures: HashMap<String, Texture2D> = HashMap::new();
+        let mut sprites = HashMap::new();
+        for def in definitions {
+            let texture = match textures.get(&def.texture_name) {
+                Some (texture) => texture.clone(),
+                None => {
+                    let texture = load_texture(&format!("content/art/{}", def.texture_name)).await?;
+                    texture.set_filter(FilterMode::Nearest);
+                    textures.insert(def.texture_name.clone(), texture.clone());
+                    texture
+                }
+            };
+            sprites.insert(def.name, Sprite {
+                texture,
+                source: Rect::new(def.x, def.y, def.width, def.height)
+            });
+        }
+        Ok (SpriteAtlas { sprites })
+    }
+
+    pub fn sprite(&self, name: &str) -> Option<&Sprite> {
+        self.sprites.get(name)
+    }
+}
@@ -1,9 +1,9 @@
 use std::sync::mpsc;
 
 use specs::prelude::*;
-use crate::components::*;
+use crate::{components::*, constants::*, resources::{CombatLog, ConnectionId, DamageNumbers, FloorMap, Fog, InterpolationDelay, Inventory, Party, PendingInputs, SpriteAtlas, Target}};
 use macroquad::prelude::*;
-use encosmo_shared::{server_components::*, Packet};
+use encosmo_shared::{map::{Map, Tile}, server_components::*, Packet, MAX_UNACKED_INPUTS};
 
 
 pub struct MoveSystem;
@@ -19,6 +19,45 @@ impl<'a> System<'a> for MoveSystem {
     }
 }
 
+/// Tab cycles through whatever we could shoot at, nearest first. Players are on our side.
+pub struct TargetingSystem;
+
+impl<'a> System<'a> for TargetingSystem {
+    type SystemData = (
+        Entities<'a>,
+        ReadStorage<'a, PlayerInput>,
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, Health>,
+        ReadStorage<'a, PlayerDetails>,
+        Write<'a, Target>
+    );
+
+    fn run(&mut self, (entities, inp, pos, health, players, mut target): Self::SystemData) {
+        // whoever we had may have died or wandered out of sight
+        if target.0.is_some_and(|entity| !entities.is_alive(entity) || !pos.contains(entity)) {
+            target.0 = None;
+        }
+        if !is_key_pressed(KeyCode::Tab) {
+            return;
+        }
+        let Some ((_, own)) = (&inp, &pos).join().next() else {
+            return;
+        };
+
+        let distance = |p: &Position| (p.x - own.x).abs().max((p.y - own.y).abs());
+        let mut candidates: Vec<(Entity, i32)> = (&entities, &pos, &health, !&players).join()
+            .filter(|(_, p, health, _)| p.floor == own.floor && !health.is_down())
+            .map(|(entity, p, ..)| (entity, distance(p)))
+            .collect();
+        candidates.sort_by_key(|(entity, distance)| (*distance, entity.id()));
+
+        let next = target.0
+            .and_then(|current| candidates.iter().position(|(entity, _)| *entity == current))
+            .map_or(0, |i| i + 1);
+        target.0 = candidates.get(next).or(candidates.first()).map(|(entity, _)| *entity);
+    }
+}
+
 pub struct InputSystem {
     pub packet_tx: mpsc::Sender<Packet>
 }
@@ -27,56 +66,284 @@ impl<'a> System<'a> for InputSystem {
     type SystemData = (
         WriteStorage<'a, Translate>,
         ReadStorage<'a, PlayerInput>,
-        ReadStorage<'a, ServerEntityId>
+        ReadStorage<'a, ServerEntityId>,
+        ReadStorage<'a, Health>,
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, FollowCamera>,
+        Write<'a, PendingInputs>,
+        Read<'a, ConnectionId>,
+        Read<'a, Party>,
+        Read<'a, Target>,
+        Read<'a, Fog>,
+        Read<'a, Inventory>,
+        Option<Read<'a, FloorMap>>
     );
 
-    fn run(&mut self, (mut vel, inp, id): Self::SystemData) {
-        for (vel, _, id) in (&mut vel, &inp, &id).join() {
+    fn run(&mut self, (mut vel, inp, id, health, pos, cam, mut pending, connection_id, party, target, fog, inventory, map): Self::SystemData) {
+        if is_key_pressed(KeyCode::P) {
+            let packet = if party.members.contains(&connection_id.0) { Packet::LeaveParty } else { Packet::JoinParty };
+            _ = self.packet_tx.send(packet);
+        }
+
+        let target = target.0.and_then(|target| id.get(target)).map(|target| target.0);
+        for (vel, _, id, health, pos, cam) in (&mut vel, &inp, &id, health.maybe(), &pos, &cam).join() {
             vel.dx = 0;
             vel.dy = 0;
+            // downed players can't do anything, the server would ignore us anyway
+            if health.is_some_and(Health::is_down) {
+                continue;
+            }
+            // the server drops inputs past this, so wait for it to catch up rather than mispredict
+            if pending.inputs.len() >= MAX_UNACKED_INPUTS {
+                continue;
+            }
             if is_key_pressed(KeyCode::Up) {
-                vel.dy -= 16;
+                vel.dy -= 1;
             }
             else if is_key_pressed(KeyCode::Down) {
-                vel.dy += 16;
+                vel.dy += 1;
             }
             else if is_key_pressed(KeyCode::Left) {
-                vel.dx -= 16;
+                vel.dx -= 1;
             }
             else if is_key_pressed(KeyCode::Right) {
-                vel.dx += 16;
+                vel.dx += 1;
             }
 
-            if vel.dx != 0 || vel.dy != 0 {
-                let packet = Packet::UpdateComponent(id.0, ServerComponentKind::Translate(vel.clone()));
-                _ = self.packet_tx.send(packet);
+            // moved locally straight away by `MoveSystem`, the server catches up later
+            if !vel.is_zero() {
+                send_input(&mut pending, &self.packet_tx, vel.clone(), |seq| Packet::Move(id.0, seq, vel.clone()));
+            }
+            // '>' and '<', the server knows which way the ladder we're on goes
+            else if is_key_pressed(KeyCode::Period) || is_key_pressed(KeyCode::Comma) {
+                send_input(&mut pending, &self.packet_tx, Translate::default(), |seq| Packet::UseLadder(id.0, seq));
+            }
+            else if is_key_pressed(KeyCode::F) {
+                let Some (target) = target else {
+                    continue;
+                };
+                send_input(&mut pending, &self.packet_tx, Translate::default(), |seq| Packet::Fire(id.0, seq, target));
+            }
+            // pass, so a server running in turns doesn't wait on us
+            else if is_key_pressed(KeyCode::Space) {
+                send_input(&mut pending, &self.packet_tx, Translate::default(), |seq| Packet::Wait(id.0, seq));
+            }
+            // the server walks us there a step at a time, and we follow along with replication
+            else if is_mouse_button_pressed(MouseButton::Left) {
+                let clicked = cam.camera.screen_to_world(mouse_position().into()) / TILE_SIZE;
+                let goal = Position { x: clicked.x.floor() as i32, y: clicked.y.floor() as i32, floor: pos.floor };
+                let explored = fog.explored.get(&goal.floor).is_some_and(|explored| explored.contains(&(goal.x, goal.y)));
+                if !explored || !map.as_ref().is_some_and(|map| map.0.is_walkable(&goal)) {
+                    continue;
+                }
+                send_input(&mut pending, &self.packet_tx, Translate::default(), |seq| Packet::TravelTo(id.0, seq, goal));
+            }
+            else if is_key_pressed(KeyCode::X) {
+                send_input(&mut pending, &self.packet_tx, Translate::default(), |seq| Packet::Explore(id.0, seq));
+            }
+            // the server knows what's lying where we stand, and tells us what we're carrying
+            else if is_key_pressed(KeyCode::G) {
+                send_input(&mut pending, &self.packet_tx, Translate::default(), |seq| Packet::PickUp(id.0, seq));
+            }
+            else if is_key_pressed(KeyCode::D) {
+                let Some ((item, ..)) = inventory.0.last() else {
+                    continue;
+                };
+                send_input(&mut pending, &self.packet_tx, Translate::default(), |seq| Packet::Drop(id.0, seq, *item));
             }
         }
     }
 }
 
+/// Numbers the input, keeps what we predicted it does until the server acknowledges it, and sends it on.
+/// Anything we can't predict, like waiting, is predicted as staying put.
+fn send_input(pending: &mut PendingInputs, tx: &mpsc::Sender<Packet>, predicted: Translate, input: impl FnOnce(u32) -> Packet) {
+    let seq = pending.next_seq;
+    pending.next_seq += 1;
+    pending.inputs.push_back((seq, predicted));
+    _ = tx.send(input(seq));
+}
+
+/// Moves remote entities smoothly between the positions the server sends us.
+/// They're drawn `InterpolationDelay` in the past, so there's usually a newer position to head towards.
+pub struct InterpolationSystem;
+impl<'a> System<'a> for InterpolationSystem {
+    type SystemData = (
+        Entities<'a>,
+        WriteStorage<'a, PositionHistory>,
+        WriteStorage<'a, RenderPosition>,
+        Read<'a, InterpolationDelay>
+    );
+
+    fn run(&mut self, (entities, mut history, mut render_pos, delay): Self::SystemData) {
+        let render_time = get_time() - delay.0;
+        for (entity, history) in (&entities, &mut history).join() {
+            let snapshots = &mut history.0;
+
+            // drop everything we've already moved past, but keep the one we're moving from
+            while snapshots.len() > 1 && snapshots[1].0 <= render_time {
+                snapshots.pop_front();
+            }
+
+            let p = match (snapshots.front(), snapshots.get(1)) {
+                // gliding across more than a tile would look odd, e.g. after a teleport, so just jump
+                (Some ((t0, from)), Some ((t1, to))) if render_time > *t0 && (to.x - from.x).abs() <= 1 && (to.y - from.y).abs() <= 1 => {
+                    let alpha = ((render_time - t0) / (t1 - t0)).clamp(0., 1.) as f32;
+                    tile_vec(from).lerp(tile_vec(to), alpha)
+                },
+                (Some ((_, from)), _) => tile_vec(from),
+                (None, _) => continue
+            };
+            _ = render_pos.insert(entity, RenderPosition(p));
+        }
+    }
+}
+
+fn tile_vec(pos: &Position) -> Vec2 {
+    vec2(pos.x as f32, pos.y as f32)
+}
+
+/// Draws everything a layer at a time, bottom first, so e.g. an item never hides the actor standing on it.
+/// The floor layer is the map itself, with floor tiles left empty, and damage numbers float over the overlay layer.
 pub struct RenderSystem;
 impl<'a> System<'a> for RenderSystem {
-    type SystemData = (ReadStorage<'a, Position>, ReadStorage<'a, Render>);
-
-    fn run(&mut self, (pos, render): Self::SystemData) {
-        for (pos, render) in (&pos, &render).join() {
-            draw_texture_ex(&render.texture, pos.x as f32, pos.y as f32, WHITE, DrawTextureParams {
-                dest_size: Some (vec2(16., 16.)),
-                source: Some (render.source),
-                ..Default::default()
-            });
+    type SystemData = (
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, RenderPosition>,
+        ReadStorage<'a, Render>,
+        ReadStorage<'a, Health>,
+        Option<Read<'a, FloorMap>>,
+        Read<'a, Fog>,
+        Write<'a, DamageNumbers>,
+        Read<'a, Target>,
+        ReadExpect<'a, SpriteAtlas>
+    );
+
+    fn run(&mut self, (pos, render_pos, render, health, floor_map, fog, mut damage_numbers, target, atlas): Self::SystemData) {
+        for layer in RenderLayer::ALL {
+            if layer == RenderLayer::Floor {
+                if let Some (floor_map) = &floor_map {
+                    draw_map(&floor_map.0, &fog, &atlas);
+                }
+            }
+            for (pos, render_pos, render, health) in (&pos, render_pos.maybe(), &render, health.maybe()).join() {
+                if render.layer == layer {
+                    let color = if health.is_some_and(Health::is_down) { DOWNED_TINT } else { WHITE };
+                    draw_sprite(&render.sprite, render_pos.map_or_else(|| tile_vec(pos), |p| p.0), color);
+                }
+            }
+            // marks whoever we'd shoot at
+            if layer == RenderLayer::Overlay {
+                let marked = target.0.and_then(|target| Some ((pos.get(target)?, render_pos.get(target))));
+                if let (Some ((pos, render_pos)), Some (sprite)) = (marked, atlas.sprite("look icon")) {
+                    draw_sprite(sprite, render_pos.map_or_else(|| tile_vec(pos), |p| p.0), WHITE);
+                }
+            }
+        }
+
+        let now = get_time();
+        damage_numbers.0.retain(|(dealt_at, _, _)| now - dealt_at < DAMAGE_NUMBER_DURATION);
+        for (dealt_at, p, amount) in &damage_numbers.0 {
+            // rises a tile as it fades
+            let age = ((now - dealt_at) / DAMAGE_NUMBER_DURATION) as f32;
+            let p = (*p - vec2(0., age)) * TILE_SIZE;
+            draw_text(&amount.to_string(), p.x + TILE_SIZE / 4., p.y, TILE_SIZE, Color { a: 1. - age, ..RED });
+        }
+    }
+}
+
+/// Our player's health and defence, drawn in a corner of the screen rather than the world, with the combat log in another.
+pub struct HudSystem;
+impl<'a> System<'a> for HudSystem {
+    type SystemData = (
+        ReadStorage<'a, PlayerInput>,
+        ReadStorage<'a, Health>,
+        ReadStorage<'a, Defence>,
+        Read<'a, CombatLog>,
+        Read<'a, Inventory>,
+        ReadExpect<'a, SpriteAtlas>
+    );
+
+    fn run(&mut self, (inp, health, defence, log, inventory, atlas): Self::SystemData) {
+        set_default_camera();
+        let line_height = TILE_SIZE * 1.25;
+        for (i, line) in log.0.iter().rev().enumerate() {
+            draw_text(line, TILE_SIZE / 2., screen_height() - TILE_SIZE / 2. - i as f32 * line_height, line_height, LIGHTGRAY);
+        }
+        for (_, health, defence) in (&inp, &health, defence.maybe()).join() {
+            if let Some (heart) = atlas.sprite("heart icon") {
+                draw_sprite(heart, vec2(0.5, 0.5), WHITE);
+            }
+            draw_text(&format!("{}/{}", health.current, health.max), 1.75 * TILE_SIZE, 1.25 * TILE_SIZE, TILE_SIZE * 1.25, WHITE);
+            if let (Some (shield), Some (defence)) = (atlas.sprite("shield icon"), defence) {
+                draw_sprite(shield, vec2(5.5, 0.5), WHITE);
+                draw_text(&defence.0.to_string(), 6.75 * TILE_SIZE, 1.25 * TILE_SIZE, TILE_SIZE * 1.25, WHITE);
+            }
+        }
+        // what we're carrying, under the stats, one line each
+        for (i, (_, details, item)) in inventory.0.iter().enumerate() {
+            let y = 2. + i as f32 * 1.25;
+            if let Some (sprite) = atlas.sprite(&details.sprite) {
+                draw_sprite(sprite, vec2(0.5, y), WHITE);
+            }
+            let label = if item.count > 1 { format!("{} x{}", details.name, item.count) } else { details.name.clone() };
+            draw_text(&label, 1.75 * TILE_SIZE, (y + 0.75) * TILE_SIZE, TILE_SIZE * 1.25, LIGHTGRAY);
+        }
+    }
+}
+
+/// Tiles in view are drawn as they are, ones only the party can see a little dimmer,
+/// ones we remember dimmer still, and ones we've never seen not at all.
+fn draw_map(map: &Map, fog: &Fog, atlas: &SpriteAtlas) {
+    let Some (explored) = fog.explored.get(&map.floor) else {
+        return;
+    };
+    for (pos, tile) in map.tiles() {
+        let xy = (pos.x, pos.y);
+        if !explored.contains(&xy) {
+            continue;
+        }
+        let (floor_color, tint) = if fog.visible.contains(&xy) {
+            (VISIBLE_FLOOR_COLOR, WHITE)
+        } else if fog.shared.contains(&xy) {
+            (PARTY_FLOOR_COLOR, PARTY_TINT)
+        } else {
+            (EXPLORED_FLOOR_COLOR, FOG_TINT)
+        };
+        let name = match tile {
+            Tile::Floor => {
+                let p = tile_vec(&pos) * TILE_SIZE;
+                draw_rectangle(p.x, p.y, TILE_SIZE, TILE_SIZE, floor_color);
+                continue;
+            },
+            Tile::Wall => "wall",
+            Tile::Water => "water",
+            Tile::DownLadder => "down ladder",
+            Tile::UpLadder => "up ladder"
+        };
+        if let Some (sprite) = atlas.sprite(name) {
+            draw_sprite(sprite, tile_vec(&pos), tint);
         }
     }
 }
 
+/// `p` is in tiles.
+fn draw_sprite(sprite: &Sprite, p: Vec2, color: Color) {
+    let p = p * TILE_SIZE;
+    draw_texture_ex(&sprite.texture, p.x, p.y, color, DrawTextureParams {
+        dest_size: Some (vec2(TILE_SIZE, TILE_SIZE)),
+        source: Some (sprite.source),
+        ..Default::default()
+    });
+}
+
 pub struct FollowCameraSystem;
 impl<'a> System<'a> for FollowCameraSystem {
     type SystemData = (WriteStorage<'a, FollowCamera>, ReadStorage<'a, Position>);
 
     fn run(&mut self, (mut cam, pos): Self::SystemData) {
         for (cam, pos) in (&mut cam, &pos).join() {
-            cam.camera.target = vec2(pos.x as f32, pos.y as f32);
+            cam.camera.target = tile_vec(pos) * TILE_SIZE;
             set_camera(&cam.camera);
         }
     }
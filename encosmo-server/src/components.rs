@@ -0,0 +1,197 @@
+// Any component in this file is a server-component in the literal sense, meaning it's only
+// used for calculations on the server and is never sent to clients.
+
+use std::collections::{HashSet, VecDeque};
+
+use encosmo_shared::{server_components::{Position, Translate}, MAX_UNACKED_INPUTS};
+use serde::Deserialize;
+use specs::prelude::*;
+
+/// Nothing else may stand on the same tile as this entity.
+#[derive(Debug, Default)]
+pub struct BlocksTile;
+impl Component for BlocksTile {
+    type Storage = NullStorage<Self>;
+}
+
+/// Tiles the entity can currently see, kept up to date by `VisibilitySystem`.
+#[derive(Debug)]
+pub struct Viewshed {
+    pub range: i32,
+    pub visible: HashSet<Position>,
+    pub origin: Option<Position>    // where `visible` was worked out from, it's only worked out again once the entity moves
+}
+impl Component for Viewshed {
+    type Storage = VecStorage<Self>;
+}
+
+impl Viewshed {
+    pub fn new(range: i32) -> Self {
+        Viewshed { range, visible: HashSet::new(), origin: None }
+    }
+}
+
+/// The player has opted in to the party, and sees whatever the rest of it sees.
+#[derive(Debug, Default)]
+pub struct PartyMember;
+impl Component for PartyMember {
+    type Storage = NullStorage<Self>;
+}
+
+/// Damage dealt to the entity this round, before its defence. Resolved all at once by `DamageSystem`.
+#[derive(Debug, Default)]
+pub struct SufferDamage {
+    pub amounts: Vec<i32>
+}
+impl Component for SufferDamage {
+    type Storage = VecStorage<Self>;
+}
+
+impl SufferDamage {
+    pub fn inflict(store: &mut WriteStorage<SufferDamage>, victim: Entity, amount: i32) {
+        if let Ok (entry) = store.entry(victim) {
+            entry.or_insert_with(SufferDamage::default).amounts.push(amount);
+        }
+    }
+}
+
+/// What the actor brings to a fight, see `CombatSystem`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CombatStats {
+    pub melee: i32,         // most damage a melee hit does, before the target's `Defence`
+    pub ranged: i32,        // most damage a ranged hit does, 0 if the actor can't shoot
+    pub range: i32,         // how far the actor can shoot, in tiles
+    pub accuracy: i32,      // chance to hit in percent, before the target's evasion
+    pub evasion: i32        // taken off the accuracy of anyone attacking the actor
+}
+impl Component for CombatStats {
+    type Storage = VecStorage<Self>;
+}
+
+/// The actor attacks `target` this round, set by bumping into it or shooting at it.
+#[derive(Debug)]
+pub struct WantsToAttack {
+    pub target: Entity,
+    pub ranged: bool
+}
+impl Component for WantsToAttack {
+    type Storage = VecStorage<Self>;
+}
+
+/// The item is in `by`'s backpack. It has no `Position` while it's there.
+#[derive(Debug)]
+pub struct Carried {
+    pub by: Entity
+}
+impl Component for Carried {
+    type Storage = VecStorage<Self>;
+}
+
+impl Carried {
+    /// How many items, or piles of them, fit in a backpack.
+    pub const MAX_ITEMS: usize = 10;
+}
+
+/// How a monster behaves, from its archetype in `content/monsters.json`. See `MonsterAiSystem`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Behaviour {
+    pub wander: bool,               // ambles about when there's nothing to chase
+    pub chase: bool,                // goes after anything hostile it can see
+    pub flee_below: Option<f32>,    // runs from whatever it can see once its health is below this fraction of its max
+    pub kite: Option<i32>           // keeps at least this many tiles from its target, and shoots it if it can
+}
+
+/// Controlled by `MonsterAiSystem`.
+#[derive(Debug)]
+pub struct Monster {
+    pub behaviour: Behaviour
+}
+impl Component for Monster {
+    type Storage = VecStorage<Self>;
+}
+
+/// How much `Energy` the actor builds up each tick.
+#[derive(Debug)]
+pub struct Speed(pub i32);
+impl Component for Speed {
+    type Storage = VecStorage<Self>;
+}
+
+impl Speed {
+    /// Players act every tick at this speed, slow horrors take longer and fast aliens can act twice.
+    pub const NORMAL: i32 = Energy::TO_ACT;
+}
+
+/// Built up by `EnergySystem` according to the actor's `Speed`, and spent on actions according to `ActionCosts`.
+/// The actor can act once it has `Energy::TO_ACT`, costly actions leave it in debt.
+#[derive(Debug)]
+pub struct Energy(pub i32);
+impl Component for Energy {
+    type Storage = VecStorage<Self>;
+}
+
+impl Energy {
+    pub const TO_ACT: i32 = 100;
+
+    /// Most that can be built up, see `EnergySystem`. Nothing gets more than two actions a tick.
+    pub const MAX: i32 = 2 * Self::TO_ACT;
+
+    pub fn can_act(&self) -> bool {
+        self.0 >= Self::TO_ACT
+    }
+}
+
+/// Somewhere a player is walking itself to, a step at a time. See `TravelSystem`.
+#[derive(Debug, Clone)]
+pub enum Destination {
+    To (Position),
+    Explore         // wherever's nearest that it hasn't seen yet, until there's nowhere left
+}
+
+/// A player walking itself somewhere. Hostiles it could already see when it set off, or last round,
+/// don't stop it, only ones that come into view.
+#[derive(Debug, Clone)]
+pub struct Travel {
+    pub to: Destination,
+    pub seen: HashSet<Entity>
+}
+impl Component for Travel {
+    type Storage = VecStorage<Self>;
+}
+
+/// Every tile the player, or its party while it was in one, has seen.
+#[derive(Debug, Default)]
+pub struct Explored(pub HashSet<Position>);
+impl Component for Explored {
+    type Storage = VecStorage<Self>;
+}
+
+/// Something a player has asked their entity to do.
+#[derive(Debug, Clone)]
+pub enum Action {
+    Move (Translate),
+    UseLadder,
+    Fire (u32),         // shoot at the entity with this id
+    Travel (Destination),   // start travelling, which is free, every step costs as much as a move
+    PickUp,             // whichever item is lying where the player is standing
+    Drop (u32),         // the carried item with this id
+    Wait            // do nothing, which in turn mode still counts as having acted
+}
+
+/// Inputs a player has sent that haven't been carried out yet, oldest first.
+/// Only one is carried out at a time, once the player has the energy for it, the rest wait their turn.
+#[derive(Debug, Default)]
+pub struct PlayerInputs {
+    pub pending: VecDeque<(u32, Action)>,
+    pub in_flight: Option<u32>      // input being carried out this round, acknowledged once it's done
+}
+impl Component for PlayerInputs {
+    type Storage = VecStorage<Self>;
+}
+
+impl PlayerInputs {
+    /// More than this and the client is either lagging badly or flooding us. The input in flight
+    /// is unacknowledged too, so a client that keeps to its limit never gets this far.
+    pub const MAX_PENDING: usize = MAX_UNACKED_INPUTS;
+}
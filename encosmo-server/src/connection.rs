@@ -1,29 +1,31 @@
 use std::sync::Arc;
 
 use anyhow::Result;
-use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::tcp::{self, OwnedReadHalf}, spawn, sync::{broadcast, mpsc, Mutex}};
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::tcp::{self, OwnedReadHalf}, spawn, sync::{broadcast, mpsc, Mutex}, time::timeout};
 use uuid::Uuid;
-use encosmo_shared::Packet;
-use crate::messages::Message;
+use encosmo_shared::{codec::{decode_packet, encode_packet, FrameDecoder, WireFormat}, handshake::{Capabilities, HANDSHAKE_TIMEOUT, PROTOCOL_VERSION}, Packet};
+use crate::{components::{Action, Destination}, messages::Message, queue::MessageQueue};
 
 pub struct Connection {
     id: Uuid,
     entity_id: u32,
     client_tx: tcp::OwnedWriteHalf,
     server_rx: mpsc::Receiver<Message>,
-    server_tx: mpsc::Sender<Message>,
+    server_tx: MessageQueue,
     broadcast_rx: broadcast::Receiver<Message>,
     self_rx: mpsc::Receiver<Message>,
     self_tx: mpsc::Sender<Message>,
-    outbox_tx: mpsc::Sender<Packet>,
-    outbox_rx: Arc<Mutex<mpsc::Receiver<Packet>>>
+    outbox_tx: mpsc::UnboundedSender<Packet>,
+    outbox_rx: Arc<Mutex<mpsc::UnboundedReceiver<Packet>>>,
+    wire_format: WireFormat     // format of the frames we send to the client
 }
 
 impl Connection {
-    pub fn new(id: Uuid, entity_id: u32, client_tx: tcp::OwnedWriteHalf, server_chan: (mpsc::Sender<Message>, mpsc::Receiver<Message>), broadcast_rx: broadcast::Receiver<Message>) -> Connection {
+    pub fn new(id: Uuid, entity_id: u32, client_tx: tcp::OwnedWriteHalf, server_chan: (MessageQueue, mpsc::Receiver<Message>), broadcast_rx: broadcast::Receiver<Message>) -> Connection {
         let (server_tx, server_rx) = server_chan;
         let (self_tx, self_rx) = mpsc::channel(100);
-        let (outbox_tx, outbox_rx) = mpsc::channel(100);
+        // unbounded since it's only drained on tick, and a burst (e.g. the world snapshot) must never block the connection
+        let (outbox_tx, outbox_rx) = mpsc::unbounded_channel();
         let outbox_rx = Arc::new(Mutex::new(outbox_rx));
 
         // resubscribe since likely some messages have been added to the channel while accepting connection
@@ -33,59 +35,64 @@ impl Connection {
             client_tx, server_rx, server_tx,
             broadcast_rx: broadcast_rx.resubscribe(),
             self_rx, self_tx,
-            outbox_tx, outbox_rx
+            outbox_tx, outbox_rx,
+            wire_format: WireFormat::default()
         }
     }
 
-    pub async fn start(&mut self, client_rx: tcp::OwnedReadHalf) -> Result<()> {
-        let server_tx = self.server_tx.clone();
-        let id = self.id;
-
+    /// `decoder` is the one used during the handshake, as it may already hold bytes of later frames.
+    /// Returns once the connection is closed, by either side. The caller tells the server, whether or not it's an error.
+    pub async fn start(&mut self, client_rx: tcp::OwnedReadHalf, decoder: FrameDecoder) -> Result<()> {
         let self_tx = self.self_tx.clone();
         
         // fire off read bytes loop
-        let mut t = spawn(recv_packet_loop(client_rx, self_tx));
+        let mut t = spawn(recv_packet_loop(client_rx, decoder, self_tx));
 
         // block on message read loop
-        loop {
-            tokio::select! {
-                msg = self.server_rx.recv() => {
-                    let msg = msg.unwrap();
-                    self.process_message(msg).await?;
-                }
-                msg = self.broadcast_rx.recv() => {
-                    let msg = msg.unwrap();
-                    self.process_message(msg).await?;
-                }
-                msg = self.self_rx.recv() => {
-                    let msg = msg.unwrap();
-                    self.process_message(msg).await?;
-                }
+        let result = loop {
+            let msg = tokio::select! {
+                msg = self.server_rx.recv() => msg,
+                msg = self.broadcast_rx.recv() => match msg {
+                    Ok (msg) => Some (msg),
+                    Err (broadcast::error::RecvError::Lagged(skipped)) => {
+                        log::warn!("Connection {} fell behind and missed {} broadcast messages", self.id, skipped);
+                        continue;
+                    },
+                    Err (broadcast::error::RecvError::Closed) => None
+                },
+                msg = self.self_rx.recv() => msg,
                 _ = &mut t => {
                     // socket closed
-                    break;
+                    break Ok (());
                 }
+            };
+            // the server has shut down
+            let Some (msg) = msg else {
+                break Ok (());
+            };
+            if let Err (e) = self.process_message(msg).await {
+                break Err (e);
             }
-        }
+        };
 
-        // loop finished indicates connection closed
-        server_tx.send(Message::PlayerDisconnected(id)).await?;
-        Ok (())
+        // stop reading from a connection we're done with
+        t.abort();
+        result
     }
 
     async fn process_message(&mut self, msg: Message) -> Result<()> {
         match msg {
             Message::Tick => self.tick().await?,
-            Message::SendPacket(p) => self.outbox_tx.send(p).await?,
+            Message::SendPacket(p) => self.outbox_tx.send(p)?,
             Message::PlayerConnected(id) => {
                 if id == self.id {
-                    self.outbox_tx.send(Packet::Id(id)).await?;
+                    self.outbox_tx.send(Packet::Id(id))?;
                 }
                 else {
-                    self.outbox_tx.send(Packet::PlayerConnected(id)).await?;
+                    self.outbox_tx.send(Packet::PlayerConnected(id))?;
                 }
             },
-            Message::PlayerDisconnected(id) => self.outbox_tx.send(Packet::PlayerDisconnected(id)).await?,
+            Message::PlayerDisconnected(id) => self.outbox_tx.send(Packet::PlayerDisconnected(id))?,
             Message::Packet(p) => self.process_packet(p).await?,
             _ => {}
         }
@@ -96,7 +103,16 @@ impl Connection {
         // send packets to client
         let mut lock = self.outbox_rx.lock().await;
         while let Ok (p) = lock.try_recv() {
-            send_packet(&mut self.client_tx, p).await?;
+            let switch_to = match p {
+                Packet::SetWireFormat(format) => Some (format),
+                _ => None
+            };
+            send_packet(&mut self.client_tx, p, self.wire_format).await?;
+
+            // the announcement itself goes out in the old format, everything after it in the new one
+            if let Some (format) = switch_to {
+                self.wire_format = format;
+            }
         }
         Ok (())
     }
@@ -104,45 +120,121 @@ impl Connection {
     async fn process_packet(&mut self, p: Packet) -> Result<()> {
         match p {
             Packet::UpdateComponent(eid, ref comp) => {
-                if eid != self.entity_id {
-                    log::warn!("Client {} attempted to update component {:?} that doesn't belong to them: {}", self.id, comp, eid);
-                } else {
-                    // TODO: send this packet to the server for further processing. Remove placeholder below.
-                    log::info!("Client {} has updated their component to {:?}", self.id, comp);
-                    self.server_tx.send(Message::Packet(p)).await?;
-                }
-            }
+                // the server is the authority on components, clients can only ask for things to happen
+                log::warn!("Client {} attempted to update component {:?} of entity {} directly", self.id, comp, eid);
+            },
+            Packet::Move(eid, seq, t) => self.input(eid, seq, Action::Move(t)),
+            Packet::UseLadder(eid, seq) => self.input(eid, seq, Action::UseLadder),
+            Packet::Wait(eid, seq) => self.input(eid, seq, Action::Wait),
+            Packet::Fire(eid, seq, target) => self.input(eid, seq, Action::Fire(target)),
+            Packet::TravelTo(eid, seq, goal) => self.input(eid, seq, Action::Travel(Destination::To(goal))),
+            Packet::Explore(eid, seq) => self.input(eid, seq, Action::Travel(Destination::Explore)),
+            Packet::PickUp(eid, seq) => self.input(eid, seq, Action::PickUp),
+            Packet::Drop(eid, seq, item) => self.input(eid, seq, Action::Drop(item)),
+            Packet::SnapshotAck(seq) => self.server_tx.push(Message::SnapshotAck(self.id, seq)),
+            Packet::JoinParty => self.server_tx.push(Message::JoinParty(self.id)),
+            Packet::LeaveParty => self.server_tx.push(Message::LeaveParty(self.id)),
+            Packet::Hello(..) => log::warn!("Client {} sent a second Hello after the handshake", self.id),
+            Packet::SetWireFormat(format) => {
+                // client has already switched; acknowledge so we switch our side too
+                log::info!("Client {} switched wire format to {:?}", self.id, format);
+                self.outbox_tx.send(Packet::SetWireFormat(format))?;
+            },
             _ => {}
         }
         Ok (())
     }
+
+    fn input(&self, eid: u32, seq: u32, action: Action) {
+        if eid != self.entity_id {
+            log::warn!("Client {} attempted to control entity that doesn't belong to them: {}", self.id, eid);
+        } else {
+            self.server_tx.push(Message::Input(eid, seq, action));
+        }
+    }
+}
+
+/// Waits for the client's `Hello` and answers it with either `Welcome` or `Disconnect`.
+/// Returns the capabilities both sides agreed on, or `None` if the client was turned away.
+/// The handshake is always spoken in json so that mismatched clients can still read why they were rejected.
+pub async fn handshake(client_rx: &mut OwnedReadHalf, client_tx: &mut tcp::OwnedWriteHalf, decoder: &mut FrameDecoder) -> Result<Option<Capabilities>> {
+    let frame = match timeout(HANDSHAKE_TIMEOUT, recv_frame(client_rx, decoder)).await {
+        Err (_) => {
+            reject(client_tx, "Timed out waiting for Hello".to_owned()).await?;
+            return Ok (None);
+        },
+        Ok (frame) => frame?
+    };
+    let Some (frame) = frame else {
+        // closed before saying anything
+        return Ok (None);
+    };
+
+    match decode_packet(&frame, WireFormat::Json) {
+        Ok (Packet::Hello(version, capabilities)) if version == PROTOCOL_VERSION => {
+            let agreed = capabilities.intersection(Capabilities::supported());
+            send_packet(client_tx, Packet::Welcome(PROTOCOL_VERSION, agreed), WireFormat::Json).await?;
+            Ok (Some (agreed))
+        },
+        Ok (Packet::Hello(version, _)) => {
+            let reason = format!("Protocol version mismatch: server speaks version {} but client speaks version {}. Please update your client.", PROTOCOL_VERSION, version);
+            reject(client_tx, reason).await?;
+            Ok (None)
+        },
+        Ok (p) => {
+            reject(client_tx, format!("Expected Hello as the first packet but got {:?}", p)).await?;
+            Ok (None)
+        },
+        Err (e) => {
+            reject(client_tx, format!("Could not understand the handshake, the client is likely incompatible with this server ({})", e)).await?;
+            Ok (None)
+        }
+    }
 }
 
-async fn recv_packet_loop(mut client_rx: OwnedReadHalf, self_tx: mpsc::Sender<Message>) -> Result<()> {
+async fn reject(client_tx: &mut tcp::OwnedWriteHalf, reason: String) -> Result<()> {
+    log::warn!("Rejecting client: {}", reason);
+    send_packet(client_tx, Packet::Disconnect(reason), WireFormat::Json).await?;
+    client_tx.shutdown().await?;
+    Ok (())
+}
+
+/// Reads until a whole frame is available. `None` means the socket was closed.
+async fn recv_frame(client_rx: &mut OwnedReadHalf, decoder: &mut FrameDecoder) -> Result<Option<Vec<u8>>> {
+    let mut buf = [0u8; 4096];
     loop {
-        let mut buf = [0u8; 1024];
+        if let Some (frame) = decoder.next_frame()? {
+            return Ok (Some (frame));
+        }
         let read = client_rx.read(&mut buf).await?;
         if read == 0 {
-            return Ok (());
+            return Ok (None);
         }
-        let res = String::from_utf8(buf[0..read].to_vec());
-        if let Err (e) = res {
-            log::error!("Error reading buffer to utf8 bytes: {}", e);
-            continue;
-        }
-        let s = res.unwrap();
-        match serde_json::from_str::<Packet>(&s.as_str()) {
-            Err (e) => log::error!("Packet deserialization failed from string {}. Error: {}", s, e),
+        decoder.extend(&buf[0..read]);
+    }
+}
+
+async fn recv_packet_loop(mut client_rx: OwnedReadHalf, mut decoder: FrameDecoder, self_tx: mpsc::Sender<Message>) -> Result<()> {
+    let mut format = WireFormat::default();
+
+    // a single read may contain any number of frames, or only part of one
+    while let Some (frame) = recv_frame(&mut client_rx, &mut decoder).await? {
+        match decode_packet(&frame, format) {
+            Err (e) => log::error!("Packet deserialization failed from {} bytes ({:?}). Error: {}", frame.len(), format, e),
             Ok (packet) => {
+                // must switch here rather than in the connection, the very next frame may already use it
+                if let Packet::SetWireFormat(f) = packet {
+                    format = f;
+                }
                 self_tx.send(Message::Packet(packet)).await?;
             }
         }
     }
+    Ok (())
 }
 
-async fn send_packet(client_tx: &mut tcp::OwnedWriteHalf, packet: Packet) -> Result<()> {
-    let s = serde_json::to_string(&packet)?;
-    let bytes = s.as_bytes();
-    client_tx.write(bytes).await?;
+async fn send_packet(client_tx: &mut tcp::OwnedWriteHalf, packet: Packet, format: WireFormat) -> Result<()> {
+    let bytes = encode_packet(&packet, format)?;
+    client_tx.write_all(&bytes).await?;
     Ok (())
-}
\ No newline at end of file
+}
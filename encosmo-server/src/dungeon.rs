@@ -0,0 +1,192 @@
+// Procedural generation of the dungeon aboard the Encosmo.
+
+use std::collections::HashSet;
+
+use encosmo_shared::{map::{Map, Tile}, server_components::Position};
+use rand::{seq::IteratorRandom, Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+pub const FLOOR_COUNT: usize = 10;
+pub const FLOOR_WIDTH: i32 = 80;
+pub const FLOOR_HEIGHT: i32 = 50;
+
+const MAX_ROOMS: usize = 30;
+const MIN_ROOM_SIZE: i32 = 5;
+const MAX_ROOM_SIZE: i32 = 12;
+const POOL_CHANCE: f64 = 0.25;     // chance of a room having a pool of water in it
+
+/// Every floor of the dungeon, top floor first. The same seed always generates the same dungeon.
+pub struct Dungeon {
+    floors: Vec<Level>
+}
+
+struct Level {
+    map: Map,
+    entrance: Position,             // middle of the first room, where the up ladder is if there is one
+    down_ladder: Option<Position>   // middle of the last room, if there's a floor below
+}
+
+impl Dungeon {
+    pub fn generate(seed: u64) -> Self {
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+        let floors = (0..FLOOR_COUNT as u32).map(|floor| generate_floor(&mut rng, floor)).collect();
+        Dungeon { floors }
+    }
+
+    pub fn floor(&self, floor: u32) -> &Map {
+        &self.floors[floor as usize].map
+    }
+
+    pub fn is_walkable(&self, pos: &Position) -> bool {
+        self.floors.get(pos.floor as usize).is_some_and(|level| level.map.is_walkable(pos))
+    }
+
+    /// Where new players arrive, on the top floor.
+    pub fn entrance(&self) -> Position {
+        self.floors[0].entrance.clone()
+    }
+
+    /// Where players arrive on the floor, at the foot of its up ladder.
+    pub fn floor_entrance(&self, floor: u32) -> &Position {
+        &self.floors[floor as usize].entrance
+    }
+
+    /// Where climbing the ladder at `pos` leads, or `None` if there's no ladder there.
+    pub fn climb(&self, pos: &Position) -> Option<Position> {
+        let floor = pos.floor as usize;
+        match self.floors.get(floor)?.map.tile(pos.x, pos.y) {
+            Tile::DownLadder => Some (self.floors.get(floor + 1)?.entrance.clone()),
+            Tile::UpLadder => self.floors.get(floor.checked_sub(1)?)?.down_ladder.clone(),
+            _ => None
+        }
+    }
+}
+
+/// The walkable tile closest to `target` on its floor that isn't in `occupied`.
+pub fn nearest_free_tile(dungeon: &Dungeon, target: &Position, occupied: &HashSet<Position>) -> Option<Position> {
+    dungeon.floors.get(target.floor as usize)?.map
+        .walkable_tiles()
+        .filter(|pos| !occupied.contains(pos))
+        .min_by_key(|pos| (pos.x - target.x).abs().max((pos.y - target.y).abs()))
+}
+
+/// Up to `n` different walkable tiles on `floor` that aren't in `occupied`, picked at random.
+pub fn random_free_tiles(dungeon: &Dungeon, floor: u32, rng: &mut impl Rng, n: usize, occupied: &HashSet<Position>) -> Vec<Position> {
+    dungeon.floor(floor)
+        .walkable_tiles()
+        .filter(|pos| !occupied.contains(pos))
+        .choose_multiple(rng, n)
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Room {
+    x: i32,
+    y: i32,
+    width: i32,
+    height: i32
+}
+
+impl Room {
+    /// Rooms need at least one tile of wall between them.
+    fn intersects(&self, other: &Room) -> bool {
+        self.x <= other.x + other.width && self.x + self.width >= other.x
+            && self.y <= other.y + other.height && self.y + self.height >= other.y
+    }
+
+    fn center(&self, floor: u32) -> Position {
+        Position { x: self.x + self.width / 2, y: self.y + self.height / 2, floor }
+    }
+}
+
+/// Rooms scattered across solid rock, each joined to the one before it by a corridor so every room can be reached.
+/// The up ladder is in the first room and the down ladder in the last, so going down means crossing the whole floor.
+fn generate_floor(rng: &mut ChaCha8Rng, floor: u32) -> Level {
+    let mut map = Map::filled(floor, FLOOR_WIDTH, FLOOR_HEIGHT, Tile::Wall);
+    let mut rooms: Vec<Room> = vec![];
+
+    for _ in 0..MAX_ROOMS {
+        let width = rng.gen_range(MIN_ROOM_SIZE..=MAX_ROOM_SIZE);
+        let height = rng.gen_range(MIN_ROOM_SIZE..=MAX_ROOM_SIZE);
+        let room = Room {
+            x: rng.gen_range(1..FLOOR_WIDTH - width - 1),
+            y: rng.gen_range(1..FLOOR_HEIGHT - height - 1),
+            width,
+            height
+        };
+        if rooms.iter().any(|other| room.intersects(other)) {
+            continue;
+        }
+
+        carve(&mut map, room, Tile::Floor);
+        if rng.gen_bool(POOL_CHANCE) {
+            // leave a ring of floor around the pool so it never cuts the room in two
+            let pool = Room {
+                x: room.x + 1,
+                y: room.y + 1,
+                width: rng.gen_range(1..=room.width - 2),
+                height: rng.gen_range(1..=room.height - 2)
+            };
+            carve(&mut map, pool, Tile::Water);
+        }
+        rooms.push(room);
+    }
+
+    // corridors are dug last, so they may cut straight through pools but never get blocked by one
+    for pair in rooms.windows(2) {
+        let (from, to) = (pair[0].center(floor), pair[1].center(floor));
+        if rng.gen_bool(0.5) {
+            dig_horizontal(&mut map, from.x, to.x, from.y);
+            dig_vertical(&mut map, from.y, to.y, to.x);
+        } else {
+            dig_vertical(&mut map, from.y, to.y, from.x);
+            dig_horizontal(&mut map, from.x, to.x, to.y);
+        }
+    }
+
+    // every room has a corridor running to its middle, so ladders there can always be reached
+    let entrance = rooms[0].center(floor);
+    if floor > 0 {
+        map.set_tile(entrance.x, entrance.y, Tile::UpLadder);
+    }
+    let down_ladder = ((floor as usize) < FLOOR_COUNT - 1).then(|| rooms[rooms.len() - 1].center(floor));
+    if let Some (ladder) = &down_ladder {
+        map.set_tile(ladder.x, ladder.y, Tile::DownLadder);
+    }
+    Level { map, entrance, down_ladder }
+}
+
+fn carve(map: &mut Map, room: Room, tile: Tile) {
+    for y in room.y..room.y + room.height {
+        for x in room.x..room.x + room.width {
+            map.set_tile(x, y, tile);
+        }
+    }
+}
+
+fn dig_horizontal(map: &mut Map, x1: i32, x2: i32, y: i32) {
+    for x in x1.min(x2)..=x1.max(x2) {
+        map.set_tile(x, y, Tile::Floor);
+    }
+}
+
+fn dig_vertical(map: &mut Map, y1: i32, y2: i32, x: i32) {
+    for y in y1.min(y2)..=y1.max(y2) {
+        map.set_tile(x, y, Tile::Floor);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_seed_same_dungeon() {
+        let (a, b) = (Dungeon::generate(42), Dungeon::generate(42));
+        for floor in 0..FLOOR_COUNT as u32 {
+            assert!(a.floor(floor).tiles().eq(b.floor(floor).tiles()), "floor {} differs", floor);
+            assert_eq!(a.floor_entrance(floor), b.floor_entrance(floor));
+        }
+        let c = Dungeon::generate(43);
+        assert!((0..FLOOR_COUNT as u32).any(|floor| !a.floor(floor).tiles().eq(c.floor(floor).tiles())));
+    }
+}
@@ -1,16 +1,101 @@
+use std::{collections::HashSet, ops::Deref};
+
 use encosmo_shared::server_components::*;
-use specs::{Builder, Entity, World, WorldExt};
+use specs::{storage::MaskedStorage, Builder, Entity, Join, ReadStorage, Storage, World, WorldExt};
 use uuid::Uuid;
 
+use crate::{components::{BlocksTile, Carried, CombatStats, Energy, Explored, Monster, PlayerInputs, Speed, Viewshed}, dungeon::{nearest_free_tile, Dungeon}, items::ItemKind, monsters::Archetype};
+
+/// How far players can see in a lit room. The Encosmo's corridors are dark and this is all the light there is.
+pub const PLAYER_VIEW_RANGE: i32 = 8;
+
+pub const PLAYER_MAX_HEALTH: i32 = 20;
+pub const PLAYER_DEFENCE: i32 = 1;
+pub const PLAYER_COMBAT_STATS: CombatStats = CombatStats { melee: 4, ranged: 3, range: 6, accuracy: 80, evasion: 10 };
+
 pub fn create_player(world: &mut World, id: Uuid) -> Entity {
+    let spawn = spawn_tile(world);
     world
         .create_entity()
         .with(Translate::default())
-        .with(Position::default())
+        .with(spawn)
+        .with(BlocksTile)
+        .with(PlayerInputs::default())
+        .with(Explored::default())
+        .with(Speed(Speed::NORMAL))
+        .with(Energy(Energy::TO_ACT))
+        .with(Health::new(PLAYER_MAX_HEALTH))
+        .with(Defence(PLAYER_DEFENCE))
+        .with(PLAYER_COMBAT_STATS)
+        .with(Viewshed::new(PLAYER_VIEW_RANGE))
         .with(PlayerDetails(id))
         .with(GameObjectDetails {
             name: "RANDO GENERATED NAME".to_owned(),
-            description: "PLACEHOLDER - see DF style rando gen descriptions".to_owned()
+            description: "PLACEHOLDER - see DF style rando gen descriptions".to_owned(),
+            sprite: "player".to_owned()
         })
         .build()
 }
+
+pub fn create_monster(world: &mut World, archetype: &Archetype, pos: Position) -> Entity {
+    world
+        .create_entity()
+        .with(Translate::default())
+        .with(pos)
+        .with(BlocksTile)
+        .with(Monster { behaviour: archetype.behaviour.clone() })
+        .with(Speed(archetype.speed))
+        .with(Energy(0))
+        .with(Health::new(archetype.health))
+        .with(Defence(archetype.defence))
+        .with(archetype.combat.clone())
+        .with(Viewshed::new(archetype.view_range))
+        .with(GameObjectDetails {
+            name: archetype.name.clone(),
+            description: archetype.description.clone(),
+            sprite: archetype.sprite.clone()
+        })
+        .build()
+}
+
+pub fn create_item(world: &mut World, kind: &ItemKind, count: u32, pos: Position) -> Entity {
+    world
+        .create_entity()
+        .with(pos)
+        .with(Item { count })
+        .with(GameObjectDetails {
+            name: kind.name.clone(),
+            description: kind.description.clone(),
+            sprite: kind.sprite.clone()
+        })
+        .build()
+}
+
+/// Leaves everything `owner` is carrying lying where it is, e.g. when its player leaves.
+pub fn drop_carried(world: &World, owner: Entity) {
+    let Some (here) = world.read_storage::<Position>().get(owner).cloned() else {
+        return;
+    };
+    let mut carried = world.write_storage::<Carried>();
+    let items: Vec<Entity> = (&world.entities(), &carried).join()
+        .filter(|(_, carried)| carried.by == owner)
+        .map(|(item, _)| item)
+        .collect();
+    let mut positions = world.write_storage::<Position>();
+    for item in items {
+        carried.remove(item);
+        _ = positions.insert(item, here.clone());
+    }
+}
+
+/// Free tile closest to the dungeon's entrance.
+fn spawn_tile(world: &World) -> Position {
+    let dungeon = world.read_resource::<Dungeon>();
+    let occupied = occupied_positions(&world.read_storage::<Position>(), &world.read_storage::<BlocksTile>());
+    nearest_free_tile(&dungeon, &dungeon.entrance(), &occupied).unwrap_or_else(|| dungeon.entrance())
+}
+
+/// Every tile with something standing on it that nothing else may share.
+pub fn occupied_positions<D: Deref<Target = MaskedStorage<Position>>>(positions: &Storage<Position, D>, blockers: &ReadStorage<BlocksTile>) -> HashSet<Position> {
+    (positions, blockers).join().map(|(pos, _)| pos.clone()).collect()
+}
@@ -0,0 +1,114 @@
+// Field of view by recursive shadowcasting, see http://www.roguebasin.com/index.php/FOV_using_recursive_shadowcasting
+
+use std::collections::HashSet;
+
+use encosmo_shared::{map::{Map, Tile}, server_components::Position};
+
+/// Transforms from the first octant into each of the eight, as (xx, xy, yx, yy).
+const OCTANTS: [(i32, i32, i32, i32); 8] = [
+    (1, 0, 0, 1), (0, 1, 1, 0), (0, -1, 1, 0), (-1, 0, 0, 1),
+    (-1, 0, 0, -1), (0, -1, -1, 0), (0, 1, -1, 0), (1, 0, 0, -1)
+];
+
+/// Every tile that can be seen from `origin` within `radius` tiles, including the walls that block the view.
+/// Only walls block sight, water can be seen across.
+pub fn field_of_view(map: &Map, origin: &Position, radius: i32) -> HashSet<Position> {
+    let mut caster = Caster { map, origin, radius, visible: HashSet::new() };
+    caster.visible.insert(origin.clone());
+    for octant in OCTANTS {
+        caster.cast_light(1, 1., 0., octant);
+    }
+    caster.visible
+}
+
+struct Caster<'a> {
+    map: &'a Map,
+    origin: &'a Position,
+    radius: i32,
+    visible: HashSet<Position>
+}
+
+impl Caster<'_> {
+    /// Scans `row` onwards of one octant between the `start` and `end` slopes, recursing whenever a wall splits the light in two.
+    fn cast_light(&mut self, row: i32, mut start: f32, end: f32, octant: (i32, i32, i32, i32)) {
+        if start < end {
+            return;
+        }
+        let (map, origin, radius) = (self.map, self.origin, self.radius);
+        let (xx, xy, yx, yy) = octant;
+        let mut new_start = 0.;
+
+        for j in row..=radius {
+            let dy = -j;
+            let mut blocked = false;
+            for dx in -j..=0 {
+                let x = origin.x + dx * xx + dy * xy;
+                let y = origin.y + dx * yx + dy * yy;
+                let left_slope = (dx as f32 - 0.5) / (dy as f32 + 0.5);
+                let right_slope = (dx as f32 + 0.5) / (dy as f32 - 0.5);
+                if start < right_slope {
+                    continue;
+                } else if end > left_slope {
+                    break;
+                }
+
+                if dx * dx + dy * dy <= radius * radius && map.in_bounds(x, y) {
+                    self.visible.insert(Position { x, y, floor: origin.floor });
+                }
+
+                let opaque = map.tile(x, y) == Tile::Wall;
+                if blocked {
+                    if opaque {
+                        new_start = right_slope;
+                    } else {
+                        blocked = false;
+                        start = new_start;
+                    }
+                } else if opaque && j < radius {
+                    // everything up to this wall is lit by a narrower beam further out
+                    blocked = true;
+                    self.cast_light(j + 1, start, left_slope, octant);
+                    new_start = right_slope;
+                }
+            }
+            if blocked {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(x: i32, y: i32) -> Position {
+        Position { x, y, floor: 0 }
+    }
+
+    #[test]
+    fn sees_everything_within_radius_in_the_open() {
+        let map = Map::filled(0, 21, 21, Tile::Floor);
+        let visible = field_of_view(&map, &at(10, 10), 5);
+        for y in 0..21 {
+            for x in 0..21 {
+                let (dx, dy) = (x - 10, y - 10);
+                assert_eq!(visible.contains(&at(x, y)), dx * dx + dy * dy <= 25, "({}, {})", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn walls_block_sight_and_water_does_not() {
+        let mut map = Map::filled(0, 21, 21, Tile::Floor);
+        for y in 7..=13 {
+            map.set_tile(13, y, Tile::Wall);
+        }
+        map.set_tile(10, 12, Tile::Water);
+        let visible = field_of_view(&map, &at(10, 10), 8);
+
+        assert!(visible.contains(&at(13, 10)), "the wall itself is seen");
+        assert!(!visible.contains(&at(15, 10)), "behind the wall isn't");
+        assert!(visible.contains(&at(10, 12)) && visible.contains(&at(10, 15)), "across water is");
+    }
+}
@@ -0,0 +1,63 @@
+// Item kinds from `content/items.json`, and leaving them lying around the dungeon.
+
+use std::fs;
+
+use anyhow::Result;
+use encosmo_shared::server_components::Position;
+use rand::{seq::SliceRandom, Rng};
+use serde::Deserialize;
+use specs::{World, WorldExt};
+
+use crate::{components::BlocksTile, dungeon::{random_free_tiles, Dungeon, FLOOR_COUNT}, entities::{create_item, occupied_positions}, resources::GameRng};
+
+/// Each floor gets this many piles of items.
+const ITEMS_PER_FLOOR: usize = 6;
+
+/// One kind of item.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ItemKind {
+    pub name: String,
+    pub description: String,
+    pub sprite: String,
+    pub floors: (u32, u32),     // first and last floor it's found on
+    pub count: (u32, u32)       // fewest and most found in one pile
+}
+
+/// Every kind of item there is.
+pub struct ItemKinds(pub Vec<ItemKind>);
+
+impl ItemKinds {
+    pub fn load(path: &str) -> Result<Self> {
+        Ok (ItemKinds(serde_json::from_str(&fs::read_to_string(path)?)?))
+    }
+}
+
+/// Scatters piles of items that belong on each floor across it. Unlike monsters, they may be right by the entrance.
+pub fn scatter(world: &mut World) {
+    let piles: Vec<(ItemKind, u32, Position)> = {
+        let dungeon = world.read_resource::<Dungeon>();
+        let kinds = world.read_resource::<ItemKinds>();
+        let rng = &mut world.write_resource::<GameRng>().0;
+        let occupied = occupied_positions(&world.read_storage::<Position>(), &world.read_storage::<BlocksTile>());
+
+        let mut piles = vec![];
+        for floor in 0..FLOOR_COUNT as u32 {
+            let kinds: Vec<&ItemKind> = kinds.0.iter()
+                .filter(|kind| (kind.floors.0..=kind.floors.1).contains(&floor))
+                .collect();
+            for pos in random_free_tiles(&dungeon, floor, rng, ITEMS_PER_FLOOR, &occupied) {
+                if let Some (kind) = kinds.choose(rng) {
+                    let count = rng.gen_range(kind.count.0..=kind.count.1);
+                    piles.push(((*kind).clone(), count, pos));
+                }
+            }
+        }
+        piles
+    };
+
+    log::info!("SERVER: scattering {} piles of items", piles.len());
+    for (kind, count, pos) in piles {
+        create_item(world, &kind, count, pos);
+    }
+}
@@ -1,5 +1,5 @@
-use server::Server;
-use std::env;
+use server::{Server, TickMode};
+use std::{env, time::Duration};
 use anyhow::Result;
 
 mod messages;
@@ -8,6 +8,16 @@ mod connection;
 mod systems;
 mod entities;
 mod resources;
+mod replication;
+mod queue;
+mod components;
+mod dungeon;
+mod fov;
+mod party;
+mod monsters;
+mod items;
+mod pathfinding;
+mod schedule;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -24,6 +34,26 @@ async fn main() -> Result<()> {
         Some (tr) => tr.parse()?
     };
 
-    let mut server = Server::new(tick_rate);
+    // the same seed always generates the same dungeon
+    let seed: u64 = match env::args().nth(3) {
+        None => rand::random(),
+        Some (s) => s.parse()?
+    };
+
+    // "turns" only moves the world on once every player has acted, or the turn timeout
+    // (in seconds) has passed. The tick rate is then how often we check
+    let mode = match env::args().nth(4).as_deref() {
+        None | Some ("realtime") => TickMode::RealTime,
+        Some ("turns") => {
+            let timeout: f64 = match env::args().nth(5) {
+                None => 30.,
+                Some (t) => t.parse()?
+            };
+            TickMode::Turns { timeout: Duration::from_secs_f64(timeout) }
+        },
+        Some (m) => anyhow::bail!("Unknown tick mode {:?}, expected \"realtime\" or \"turns\"", m)
+    };
+
+    let mut server = Server::new(tick_rate, seed, mode);
     server.start(port).await
 }
@@ -1,14 +1,20 @@
 use encosmo_shared::Packet;
 use uuid::Uuid;
 
+use crate::components::Action;
 
 /// messages to send between actors, NOT packets to be sent to clients
 #[derive(Clone, Debug)]
 pub enum Message {
     SendPacket (Packet),        // packet to be sent to the client
+    SendPacketTo (Uuid, Packet),    // packet to be sent to one particular client only
     Packet (Packet),            // packet that has been received from the client
     BroadcastPacket (Packet),   // packet to be broadcasted
     Tick,
     PlayerConnected (Uuid),
-    PlayerDisconnected (Uuid)
+    PlayerDisconnected (Uuid),
+    Input (u32, u32, Action),   // entity (id), which belongs to whoever sent it, wants to carry out input (seq)
+    SnapshotAck (Uuid, u32),    // player (id) has applied every snapshot up to and including (seq)
+    JoinParty (Uuid),
+    LeaveParty (Uuid)
 }
@@ -0,0 +1,75 @@
+// Monster archetypes from `content/monsters.json`, and filling the dungeon with them.
+
+use std::fs;
+
+use anyhow::Result;
+use encosmo_shared::server_components::Position;
+use rand::seq::SliceRandom;
+use serde::Deserialize;
+use specs::{World, WorldExt};
+
+use crate::{components::{Behaviour, BlocksTile, CombatStats}, dungeon::{random_free_tiles, Dungeon, FLOOR_COUNT}, entities::{create_monster, occupied_positions}, resources::GameRng};
+
+/// Each floor gets this many monsters, plus one for every floor further down.
+const MONSTERS_PER_FLOOR: usize = 4;
+
+/// No monsters start this close to where players arrive on a floor, in tiles.
+const SAFE_DISTANCE: i32 = 12;
+
+/// One kind of monster.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Archetype {
+    pub name: String,
+    pub description: String,
+    pub sprite: String,
+    pub floors: (u32, u32),     // first and last floor it's found on
+    pub health: i32,
+    pub defence: i32,
+    pub speed: i32,
+    pub view_range: i32,
+    pub combat: CombatStats,
+    pub behaviour: Behaviour
+}
+
+/// Every kind of monster there is.
+pub struct Bestiary(pub Vec<Archetype>);
+
+impl Bestiary {
+    pub fn load(path: &str) -> Result<Self> {
+        Ok (Bestiary(serde_json::from_str(&fs::read_to_string(path)?)?))
+    }
+}
+
+/// Scatters monsters that belong on each floor across it, away from the entrance.
+pub fn populate(world: &mut World) {
+    let spawns: Vec<(Archetype, Position)> = {
+        let dungeon = world.read_resource::<Dungeon>();
+        let bestiary = world.read_resource::<Bestiary>();
+        let rng = &mut world.write_resource::<GameRng>().0;
+        let occupied = occupied_positions(&world.read_storage::<Position>(), &world.read_storage::<BlocksTile>());
+
+        let mut spawns = vec![];
+        for floor in 0..FLOOR_COUNT as u32 {
+            let archetypes: Vec<&Archetype> = bestiary.0.iter()
+                .filter(|archetype| (archetype.floors.0..=archetype.floors.1).contains(&floor))
+                .collect();
+            // the tiles around the entrance are kept clear as if something was already standing there
+            let entrance = dungeon.floor_entrance(floor);
+            let mut taken = occupied.clone();
+            taken.extend(dungeon.floor(floor).walkable_tiles()
+                .filter(|pos| (pos.x - entrance.x).abs().max((pos.y - entrance.y).abs()) <= SAFE_DISTANCE));
+            for pos in random_free_tiles(&dungeon, floor, rng, MONSTERS_PER_FLOOR + floor as usize, &taken) {
+                if let Some (archetype) = archetypes.choose(rng) {
+                    spawns.push(((*archetype).clone(), pos));
+                }
+            }
+        }
+        spawns
+    };
+
+    log::info!("SERVER: spawning {} monsters", spawns.len());
+    for (archetype, pos) in spawns {
+        create_monster(world, &archetype, pos);
+    }
+}
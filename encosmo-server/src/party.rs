@@ -0,0 +1,78 @@
+// The one opt-in party players can join to share what they see with each other.
+
+use std::{collections::HashSet, ops::Deref};
+
+use encosmo_shared::{server_components::*, Packet};
+use specs::{prelude::*, storage::MaskedStorage};
+use uuid::Uuid;
+
+use crate::{components::{PartyMember, Viewshed}, messages::Message, resources::ServerTx};
+
+/// One for each cosmonaut sent to board the Encosmo.
+pub const MAX_PARTY_SIZE: usize = 4;
+
+/// Adds the player's entity to the party, unless it's already full. Returns whether they're in the party now.
+pub fn join(world: &mut World, entity: Entity) -> bool {
+    let members = world.read_storage::<PartyMember>();
+    if members.contains(entity) {
+        return true;
+    }
+    if members.count() >= MAX_PARTY_SIZE {
+        return false;
+    }
+    drop(members);
+    _ = world.write_storage::<PartyMember>().insert(entity, PartyMember);
+    party_changed(world, None);
+    true
+}
+
+pub fn leave(world: &mut World, entity: Entity) {
+    if world.write_storage::<PartyMember>().remove(entity).is_some() {
+        party_changed(world, Some (entity));
+    }
+}
+
+/// The connection ids of everyone in the party.
+pub fn members(world: &World) -> Vec<Uuid> {
+    (&world.read_storage::<PartyMember>(), &world.read_storage::<PlayerDetails>()).join()
+        .map(|(_, player)| player.0)
+        .collect()
+}
+
+/// Everyone in the party, and whoever just left it, sees differently now.
+/// Their vision is worked out again next tick and they're told who's in the party.
+pub fn party_changed(world: &World, left: Option<Entity>) {
+    let entities = world.entities();
+    let players = world.read_storage::<PlayerDetails>();
+    let mut viewsheds = world.write_storage::<Viewshed>();
+    let tx = &world.read_resource::<ServerTx>().0;
+
+    let ids = members(world);
+    let party = world.read_storage::<PartyMember>();
+    for (entity, player, viewshed) in (&entities, &players, &mut viewsheds).join() {
+        let member = party.contains(entity);
+        if member || Some (entity) == left {
+            viewshed.origin = None;
+            let ids = if member { ids.clone() } else { vec![] };
+            tx.push(Message::SendPacketTo(player.0, Packet::PartyMembers(ids)));
+        }
+    }
+}
+
+/// Tiles on the entity's floor its party can see but it can't, empty if it isn't in the party.
+pub fn shared_vision<D>(entity: Entity, viewsheds: &Storage<Viewshed, D>, members: &ReadStorage<PartyMember>) -> HashSet<Position>
+where
+    D: Deref<Target = MaskedStorage<Viewshed>>
+{
+    let Some (own) = viewsheds.get(entity).filter(|_| members.contains(entity)) else {
+        return HashSet::new();
+    };
+    let Some (floor) = own.origin.as_ref().map(|origin| origin.floor) else {
+        return HashSet::new();
+    };
+    (viewsheds, members).join()
+        .flat_map(|(viewshed, _)| viewshed.visible.iter())
+        .filter(|pos| pos.floor == floor && !own.visible.contains(pos))
+        .cloned()
+        .collect()
+}
@@ -0,0 +1,221 @@
+// Finding ways through the dungeon: A* from one tile to another, and Dijkstra maps for heading towards or
+// away from several things at once. Diagonal steps cost the same as straight ones, as they do in `MoveSystem`.
+
+use std::{cmp::Reverse, collections::{BinaryHeap, HashMap, HashSet}};
+
+use encosmo_shared::{map::Map, server_components::Position};
+
+use crate::dungeon::Dungeon;
+
+/// Costs are in tenths of a step, so `DijkstraMap::flee` can scale them without rounding everything away.
+const STEP_COST: i32 = 10;
+
+/// Extra cost of going through a tile someone is standing in. Paths go around a crowd when they can, but aren't cut off by one.
+const OCCUPIED_COST: i32 = 5 * STEP_COST;
+
+/// How much further than the way out fleeing may go to get further away in the end, see `DijkstraMap::flee`.
+const FLEE_SCALE: i32 = -12;    // in tenths
+
+const DIRECTIONS: [(i32, i32); 8] = [(-1, -1), (0, -1), (1, -1), (-1, 0), (1, 0), (-1, 1), (0, 1), (1, 1)];
+
+/// The tiles from `from` to `to`, not including `from`, or `None` if there's no way there on the same floor.
+pub fn a_star(map: &Map, occupied: &HashSet<Position>, from: &Position, to: &Position) -> Option<Vec<Position>> {
+    if from.floor != map.floor || to.floor != map.floor || !map.is_walkable(to) {
+        return None;
+    }
+    let (start, goal) = ((from.x, from.y), (to.x, to.y));
+    let heuristic = |(x, y): (i32, i32)| (x - goal.0).abs().max((y - goal.1).abs()) * STEP_COST;
+
+    let mut open = BinaryHeap::from([Reverse ((heuristic(start), start))]);
+    let mut costs = HashMap::from([(start, 0)]);
+    let mut came_from: HashMap<(i32, i32), (i32, i32)> = HashMap::new();
+    while let Some (Reverse ((_, current))) = open.pop() {
+        if current == goal {
+            let mut path = vec![];
+            let mut tile = current;
+            while tile != start {
+                path.push(Position { x: tile.0, y: tile.1, floor: map.floor });
+                tile = came_from[&tile];
+            }
+            path.reverse();
+            return Some (path);
+        }
+
+        let cost = costs[&current];
+        for (dx, dy) in DIRECTIONS {
+            let next = (current.0 + dx, current.1 + dy);
+            if !map.tile(next.0, next.1).is_walkable() {
+                continue;
+            }
+            // the goal is usually occupied, by whoever's being chased
+            let crowded = next != goal && occupied.contains(&Position { x: next.0, y: next.1, floor: map.floor });
+            let next_cost = cost + STEP_COST + if crowded { OCCUPIED_COST } else { 0 };
+            if costs.get(&next).is_none_or(|known| next_cost < *known) {
+                costs.insert(next, next_cost);
+                came_from.insert(next, current);
+                open.push(Reverse ((next_cost + heuristic(next), next)));
+            }
+        }
+    }
+    None
+}
+
+/// How far every tile on a floor is from the nearest of some goals. Walking downhill leads to the closest one.
+/// Only the map is taken into account, not who's standing where, so one map serves everyone on the floor.
+pub struct DijkstraMap {
+    floor: u32,
+    width: i32,
+    values: Vec<i32>    // `i32::MAX` where there's no way to any goal
+}
+
+impl DijkstraMap {
+    pub fn new(map: &Map, goals: &[Position]) -> Self {
+        let seeds = goals.iter().filter(|goal| map.is_walkable(goal)).map(|goal| ((goal.x, goal.y), 0)).collect();
+        DijkstraMap { floor: map.floor, width: map.width, values: relax(map, seeds) }
+    }
+
+    /// Walking downhill leads away from all the goals at once. Distances are flipped and scaled up, then smoothed out again,
+    /// so the way downhill may pass closer to a goal if it leads somewhere safer, rather than into the nearest dead end.
+    pub fn flee(map: &Map, goals: &[Position]) -> Self {
+        let towards = DijkstraMap::new(map, goals);
+        let seeds = map.walkable_tiles()
+            .filter_map(|pos| Some (((pos.x, pos.y), towards.value(&pos)? * FLEE_SCALE / 10)))
+            .collect();
+        DijkstraMap { floor: map.floor, width: map.width, values: relax(map, seeds) }
+    }
+
+    /// `None` if the tile can't reach any goal, or is on another floor.
+    pub fn value(&self, pos: &Position) -> Option<i32> {
+        if pos.floor != self.floor || pos.x < 0 || pos.x >= self.width {
+            return None;
+        }
+        self.values.get((pos.y * self.width + pos.x) as usize).copied().filter(|value| *value != i32::MAX)
+    }
+}
+
+/// Lowers every walkable tile to at most one step more than its lowest neighbour, starting from the seeds.
+fn relax(map: &Map, seeds: Vec<((i32, i32), i32)>) -> Vec<i32> {
+    let index = |(x, y): (i32, i32)| (y * map.width + x) as usize;
+    let mut values = vec![i32::MAX; (map.width * map.height) as usize];
+    let mut open = BinaryHeap::new();
+    for (tile, value) in seeds {
+        if value < values[index(tile)] {
+            values[index(tile)] = value;
+            open.push(Reverse ((value, tile)));
+        }
+    }
+
+    while let Some (Reverse ((value, tile))) = open.pop() {
+        if value > values[index(tile)] {
+            continue;
+        }
+        for (dx, dy) in DIRECTIONS {
+            let next = (tile.0 + dx, tile.1 + dy);
+            if map.tile(next.0, next.1).is_walkable() && value + STEP_COST < values[index(next)] {
+                values[index(next)] = value + STEP_COST;
+                open.push(Reverse ((value + STEP_COST, next)));
+            }
+        }
+    }
+    values
+}
+
+/// Paths and flee maps worked out this round, so everyone after the same thing shares one search.
+/// Forgotten at the start of every round by `PathCacheSystem`, since by then things will have moved.
+#[derive(Default)]
+pub struct PathCache {
+    paths: HashMap<(Position, Position), Option<Vec<Position>>>,
+    flee_maps: HashMap<u32, DijkstraMap>
+}
+
+impl PathCache {
+    pub fn clear(&mut self) {
+        self.paths.clear();
+        self.flee_maps.clear();
+    }
+
+    /// See `a_star`. Occupancy is whatever it was the first time the path was asked for this round.
+    pub fn path(&mut self, dungeon: &Dungeon, occupied: &HashSet<Position>, from: &Position, to: &Position) -> Option<&[Position]> {
+        self.paths
+            .entry((from.clone(), to.clone()))
+            .or_insert_with(|| a_star(dungeon.floor(from.floor), occupied, from, to))
+            .as_deref()
+    }
+
+    /// A flee map for getting away from every player on the floor. `players` is only asked for if there isn't one yet this round.
+    pub fn flee_from_players(&mut self, dungeon: &Dungeon, floor: u32, players: impl FnOnce() -> Vec<Position>) -> &DijkstraMap {
+        self.flee_maps
+            .entry(floor)
+            .or_insert_with(|| DijkstraMap::flee(dungeon.floor(floor), &players()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use encosmo_shared::map::Tile;
+
+    use super::*;
+
+    fn at(x: i32, y: i32) -> Position {
+        Position { x, y, floor: 0 }
+    }
+
+    /// An open room with a wall down the middle at x = 5, with a gap at `gap` if there is one.
+    fn divided(gap: Option<i32>) -> Map {
+        let mut map = Map::filled(0, 11, 11, Tile::Floor);
+        for y in 0..11 {
+            if Some (y) != gap {
+                map.set_tile(5, y, Tile::Wall);
+            }
+        }
+        map
+    }
+
+    #[test]
+    fn a_star_takes_the_shortest_way() {
+        let map = Map::filled(0, 11, 11, Tile::Floor);
+        let path = a_star(&map, &HashSet::new(), &at(1, 1), &at(7, 4)).unwrap();
+        // diagonals cost the same as straight steps
+        assert_eq!(path.len(), 6);
+        assert_eq!(path.last(), Some (&at(7, 4)));
+        assert!(!path.contains(&at(1, 1)));
+    }
+
+    #[test]
+    fn a_star_goes_through_gaps_and_gives_up_without_one() {
+        let path = a_star(&divided(Some (8)), &HashSet::new(), &at(2, 2), &at(8, 2)).unwrap();
+        assert!(path.contains(&at(5, 8)));
+        for (a, b) in path.iter().zip(path.iter().skip(1)) {
+            assert!((a.x - b.x).abs() <= 1 && (a.y - b.y).abs() <= 1, "{:?} to {:?} isn't a step", a, b);
+        }
+        assert!(a_star(&divided(None), &HashSet::new(), &at(2, 2), &at(8, 2)).is_none());
+    }
+
+    #[test]
+    fn a_star_goes_around_a_crowd_but_not_the_goal() {
+        let map = Map::filled(0, 11, 11, Tile::Floor);
+        let occupied = HashSet::from([at(3, 5), at(5, 5)]);
+        let path = a_star(&map, &occupied, &at(1, 5), &at(5, 5)).unwrap();
+        assert!(!path.contains(&at(3, 5)));
+        assert_eq!(path.last(), Some (&at(5, 5)));
+    }
+
+    #[test]
+    fn dijkstra_map_counts_steps_to_the_nearest_goal() {
+        let map = divided(Some (8));
+        let towards = DijkstraMap::new(&map, &[at(8, 8), at(2, 2)]);
+        assert_eq!(towards.value(&at(2, 2)), Some (0));
+        assert_eq!(towards.value(&at(4, 2)), Some (2 * STEP_COST));
+        assert_eq!(towards.value(&at(8, 5)), Some (3 * STEP_COST));
+        assert_eq!(towards.value(&at(5, 2)), None, "walls can't reach anything");
+        assert_eq!(towards.value(&Position { x: 2, y: 2, floor: 1 }), None);
+    }
+
+    #[test]
+    fn fleeing_leads_further_away() {
+        let map = Map::filled(0, 11, 11, Tile::Floor);
+        let away = DijkstraMap::flee(&map, &[at(2, 5)]);
+        let value = |x| away.value(&at(x, 5)).unwrap();
+        assert!(value(4) < value(3) && value(8) < value(4) && value(10) < value(8));
+    }
+}
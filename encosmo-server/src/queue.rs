@@ -0,0 +1,45 @@
+use std::{collections::VecDeque, sync::{Arc, Mutex}, time::Instant};
+
+use crate::messages::Message;
+
+/// A message along with when it was queued.
+/// `seq` is unique and strictly increasing in the order messages were pushed.
+#[derive(Debug)]
+pub struct Envelope {
+    pub seq: u64,
+    pub queued_at: Instant,
+    pub msg: Message
+}
+
+#[derive(Default)]
+struct Inner {
+    next_seq: u64,
+    messages: VecDeque<Envelope>
+}
+
+/// The server's one inbound queue, shared by connections (async) and specs systems (sync).
+///
+/// Pushing never blocks on anything but the queue's own lock, which is only held long enough
+/// to stamp and append, so it is safe to call from anywhere. The server drains it once per tick:
+/// everything queued before the drain is handled that tick in `seq` order, anything queued
+/// while it is being handled waits for the next one.
+#[derive(Clone, Default)]
+pub struct MessageQueue(Arc<Mutex<Inner>>);
+
+impl MessageQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&self, msg: Message) {
+        let mut inner = self.0.lock().unwrap();
+        let seq = inner.next_seq;
+        inner.next_seq += 1;
+        inner.messages.push_back(Envelope { seq, queued_at: Instant::now(), msg });
+    }
+
+    /// Takes every message queued so far, oldest first.
+    pub fn drain(&self) -> VecDeque<Envelope> {
+        std::mem::take(&mut self.0.lock().unwrap().messages)
+    }
+}
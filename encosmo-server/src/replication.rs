@@ -0,0 +1,381 @@
+use std::{collections::{HashMap, HashSet, VecDeque}, ops::BitOrAssign};
+
+use encosmo_shared::{server_components::*, Packet, Snapshot};
+use specs::prelude::*;
+use uuid::Uuid;
+
+use crate::{components::{PartyMember, Viewshed}, messages::Message, party::shared_vision, resources::ServerTx};
+
+/// Which replicated components of an entity changed, one bit each.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ComponentMask(u8);
+
+impl ComponentMask {
+    pub const POSITION: ComponentMask = ComponentMask(1 << 0);
+    pub const GAME_OBJECT_DETAILS: ComponentMask = ComponentMask(1 << 1);
+    pub const PLAYER_DETAILS: ComponentMask = ComponentMask(1 << 2);
+    pub const HEALTH: ComponentMask = ComponentMask(1 << 3);
+    pub const DEFENCE: ComponentMask = ComponentMask(1 << 4);
+    pub const ITEM: ComponentMask = ComponentMask(1 << 5);
+    pub const ALL: ComponentMask = ComponentMask(0b111111);
+
+    pub fn contains(self, other: ComponentMask) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOrAssign for ComponentMask {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
+}
+
+/// What changed in the replicated world during one tick.
+#[derive(Debug, Default)]
+struct TickChanges {
+    tick: u32,
+    changed: HashMap<u32, ComponentMask>,
+    removed: HashSet<u32>       // had a component removed, which means the entity was deleted or its `Position` taken away
+}
+
+/// The last `MAX_HISTORY` ticks worth of changes, so deltas can be built against any snapshot a client may have acked.
+#[derive(Debug, Default)]
+pub struct ReplicationLog {
+    tick: u32,
+    history: VecDeque<TickChanges>
+}
+
+impl ReplicationLog {
+    /// 16 seconds at the default tick rate. Clients that take longer than this to ack get a full snapshot instead.
+    pub const MAX_HISTORY: usize = 32;
+
+    /// Everything that changed after `baseline`, or `None` if that's further back than we remember.
+    fn changes_since(&self, baseline: u32) -> Option<(HashMap<u32, ComponentMask>, HashSet<u32>)> {
+        let oldest = self.history.front().map_or(self.tick, |changes| changes.tick);
+        if baseline + 1 < oldest {
+            return None;
+        }
+
+        let mut changed: HashMap<u32, ComponentMask> = HashMap::new();
+        let mut removed = HashSet::new();
+        for changes in self.history.iter().filter(|changes| changes.tick > baseline) {
+            for (eid, mask) in &changes.changed {
+                *changed.entry(*eid).or_default() |= *mask;
+            }
+            removed.extend(&changes.removed);
+        }
+        Some ((changed, removed))
+    }
+}
+
+
+/// What the server knows about one client's copy of the world.
+#[derive(Debug)]
+pub struct ClientView {
+    entity: Entity,             // the client's player, the client is told about whatever it or its party can see
+    known: HashSet<u32>,        // entities the client has been sent and not yet told to despawn
+    acked: Option<u32>,         // latest snapshot the client has applied
+    full_sent: Option<u32>      // latest full snapshot we've sent, which the client applies before anything we send after it
+}
+
+impl ClientView {
+    pub fn new(entity: Entity) -> Self {
+        ClientView { entity, known: HashSet::new(), acked: None, full_sent: None }
+    }
+
+    /// The snapshot the next delta is built against. The stream is ordered, so a full snapshot
+    /// can be built upon before it's acked; acks only keep deltas from growing.
+    fn baseline(&self) -> Option<u32> {
+        self.acked.max(self.full_sent)
+    }
+
+    pub fn ack(&mut self, seq: u32) {
+        self.acked = self.acked.max(Some (seq));
+    }
+
+    /// Whether the client has the entity, so it's worth telling about what happens to it.
+    pub fn knows(&self, eid: u32) -> bool {
+        self.known.contains(&eid)
+    }
+}
+
+/// Every connected client's `ClientView`, added once their player exists and removed when they leave.
+#[derive(Default)]
+pub struct ClientViews(pub HashMap<Uuid, ClientView>);
+
+impl ClientViews {
+    /// Sends the packet to every client that has any of the entities, e.g. to show what happened to them.
+    pub fn notify(&self, tx: &ServerTx, eids: &[u32], p: Packet) {
+        for (id, view) in &self.0 {
+            if eids.iter().any(|eid| view.knows(*eid)) {
+                tx.0.push(Message::SendPacketTo(*id, p.clone()));
+            }
+        }
+    }
+}
+
+/// Records which replicated components changed this tick, then sends each client whatever it hasn't acked yet
+/// about the entities its player, or its player's party, can see. Runs last, so a snapshot reflects everything the other systems did during the tick.
+#[derive(Default)]
+pub struct ReplicationSystem {
+    readers: Option<[ReaderId<ComponentEvent>; 6]>
+}
+
+impl<'a> System<'a> for ReplicationSystem {
+    type SystemData = (
+        Entities<'a>,
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, GameObjectDetails>,
+        ReadStorage<'a, PlayerDetails>,
+        ReadStorage<'a, Health>,
+        ReadStorage<'a, Defence>,
+        ReadStorage<'a, Item>,
+        ReadStorage<'a, Viewshed>,
+        ReadStorage<'a, PartyMember>,
+        Write<'a, ReplicationLog>,
+        Write<'a, ClientViews>,
+        ReadExpect<'a, ServerTx>
+    );
+
+    fn setup(&mut self, world: &mut World) {
+        Self::SystemData::setup(world);
+        self.readers = Some ([
+            WriteStorage::<Position>::fetch(world).register_reader(),
+            WriteStorage::<GameObjectDetails>::fetch(world).register_reader(),
+            WriteStorage::<PlayerDetails>::fetch(world).register_reader(),
+            WriteStorage::<Health>::fetch(world).register_reader(),
+            WriteStorage::<Defence>::fetch(world).register_reader(),
+            WriteStorage::<Item>::fetch(world).register_reader()
+        ]);
+    }
+
+    fn run(&mut self, (entities, pos, details, players, health, defence, items, viewsheds, members, mut log, mut views, res): Self::SystemData) {
+        let readers = self.readers.as_mut().expect("ReplicationSystem::setup has not been called");
+
+        log.tick += 1;
+        let mut changes = TickChanges { tick: log.tick, ..Default::default() };
+        let channels = [
+            (pos.channel(), ComponentMask::POSITION),
+            (details.channel(), ComponentMask::GAME_OBJECT_DETAILS),
+            (players.channel(), ComponentMask::PLAYER_DETAILS),
+            (health.channel(), ComponentMask::HEALTH),
+            (defence.channel(), ComponentMask::DEFENCE),
+            (items.channel(), ComponentMask::ITEM)
+        ];
+        for ((channel, mask), reader) in channels.into_iter().zip(readers.iter_mut()) {
+            for event in channel.read(reader) {
+                match event {
+                    ComponentEvent::Inserted(id) | ComponentEvent::Modified(id) => *changes.changed.entry(*id).or_default() |= mask,
+                    ComponentEvent::Removed(id) => _ = changes.removed.insert(*id)
+                }
+            }
+        }
+        log.history.push_back(changes);
+        while log.history.len() > ReplicationLog::MAX_HISTORY {
+            log.history.pop_front();
+        }
+
+        let storages = ReplicatedStorages { pos: &pos, details: &details, players: &players, health: &health, defence: &defence, items: &items };
+        for (id, view) in views.0.iter_mut() {
+            // entities without a `Position` can't be seen, and so are never replicated
+            let Some (viewshed) = viewsheds.get(view.entity) else {
+                continue;
+            };
+            let shared = shared_vision(view.entity, &viewsheds, &members);
+            let relevant: HashSet<u32> = (&entities, &pos).join()
+                .filter(|(entity, pos)| *entity == view.entity || viewshed.visible.contains(pos) || shared.contains(pos))
+                .map(|(entity, _)| entity.id())
+                .collect();
+
+            let delta = view.baseline().and_then(|baseline| Some ((baseline, log.changes_since(baseline)?)));
+            let snapshot = match delta {
+                Some ((baseline, (changed, removed))) => {
+                    // a removed id the client knows that's alive again was reused by a new entity, which it needs in full
+                    let reused: HashSet<u32> = relevant.iter()
+                        .filter(|eid| removed.contains(eid) && view.known.contains(eid))
+                        .copied()
+                        .collect();
+
+                    // entities that left relevance are despawned on the client, ones that entered are sent in full
+                    let despawned: Vec<u32> = view.known.iter()
+                        .filter(|eid| !relevant.contains(eid))
+                        .chain(&reused)
+                        .copied()
+                        .collect();
+                    let upserts: Vec<_> = relevant.iter()
+                        .filter_map(|eid| {
+                            let mask = if reused.contains(eid) || !view.known.contains(eid) { ComponentMask::ALL } else { *changed.get(eid)? };
+                            Some ((*eid, replicated_components(storages, entities.entity(*eid), mask)))
+                        })
+                        .collect();
+
+                    if despawned.is_empty() && upserts.is_empty() {
+                        // nothing the client doesn't already have, so there's nothing to send
+                        view.acked = Some (log.tick);
+                        continue;
+                    }
+                    Snapshot { seq: log.tick, baseline: Some (baseline), removed: despawned, entities: upserts }
+                },
+                None => {
+                    view.full_sent = Some (log.tick);
+                    let upserts = relevant.iter()
+                        .map(|eid| (*eid, replicated_components(storages, entities.entity(*eid), ComponentMask::ALL)))
+                        .collect();
+                    Snapshot { seq: log.tick, baseline: None, removed: vec![], entities: upserts }
+                }
+            };
+            view.known = relevant;
+            res.0.push(Message::SendPacketTo(*id, Packet::Snapshot(snapshot)));
+        }
+    }
+}
+
+/// Every replicated component's storage, one for each bit of `ComponentMask`.
+#[derive(Clone, Copy)]
+struct ReplicatedStorages<'s, 'a> {
+    pos: &'s ReadStorage<'a, Position>,
+    details: &'s ReadStorage<'a, GameObjectDetails>,
+    players: &'s ReadStorage<'a, PlayerDetails>,
+    health: &'s ReadStorage<'a, Health>,
+    defence: &'s ReadStorage<'a, Defence>,
+    items: &'s ReadStorage<'a, Item>
+}
+
+/// The entity's replicated components picked out by `mask`, in a form that can be sent to clients.
+fn replicated_components(storages: ReplicatedStorages, entity: Entity, mask: ComponentMask) -> Vec<ServerComponentKind> {
+    let mut components = vec![];
+    if let Some (pos) = storages.pos.get(entity).filter(|_| mask.contains(ComponentMask::POSITION)) {
+        components.push(ServerComponentKind::Position(pos.clone()));
+    }
+    if let Some (details) = storages.details.get(entity).filter(|_| mask.contains(ComponentMask::GAME_OBJECT_DETAILS)) {
+        components.push(ServerComponentKind::GameObjectDetails(details.clone()));
+    }
+    if let Some (details) = storages.players.get(entity).filter(|_| mask.contains(ComponentMask::PLAYER_DETAILS)) {
+        components.push(ServerComponentKind::PlayerDetails(details.clone()));
+    }
+    if let Some (health) = storages.health.get(entity).filter(|_| mask.contains(ComponentMask::HEALTH)) {
+        components.push(ServerComponentKind::Health(health.clone()));
+    }
+    if let Some (defence) = storages.defence.get(entity).filter(|_| mask.contains(ComponentMask::DEFENCE)) {
+        components.push(ServerComponentKind::Defence(defence.clone()));
+    }
+    if let Some (item) = storages.items.get(entity).filter(|_| mask.contains(ComponentMask::ITEM)) {
+        components.push(ServerComponentKind::Item(item.clone()));
+    }
+    components
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::queue::MessageQueue;
+
+    use super::*;
+
+    const CLIENT: Uuid = Uuid::from_u128(1);
+
+    /// A world with one client, whose player stands at the origin and sees `sight`.
+    fn world(sight: Vec<Position>) -> (World, ReplicationSystem, Entity) {
+        let mut world = World::new();
+        world.insert(ServerTx(MessageQueue::new()));
+        let mut system = ReplicationSystem::default();
+        System::setup(&mut system, &mut world);
+
+        let player = world.create_entity()
+            .with(Position { x: 0, y: 0, floor: 0 })
+            .with(Viewshed { range: 8, visible: sight.into_iter().collect(), origin: None })
+            .build();
+        world.write_resource::<ClientViews>().0.insert(CLIENT, ClientView::new(player));
+        (world, system, player)
+    }
+
+    fn monster(world: &mut World, pos: Position) -> Entity {
+        world.create_entity()
+            .with(pos)
+            .with(GameObjectDetails { name: "Scuttler".to_owned(), description: String::new(), sprite: String::new() })
+            .with(Health { current: 5, max: 5 })
+            .build()
+    }
+
+    /// Runs replication for a tick and returns the snapshot the client was sent, if any.
+    fn tick(world: &mut World, system: &mut ReplicationSystem) -> Option<Snapshot> {
+        system.run_now(world);
+        world.maintain();
+        world.read_resource::<ServerTx>().0.drain().into_iter().find_map(|envelope| match envelope.msg {
+            Message::SendPacketTo(CLIENT, Packet::Snapshot(snapshot)) => Some (snapshot),
+            _ => None
+        })
+    }
+
+    fn step(world: &mut World, entity: Entity) {
+        world.write_storage::<Position>().get_mut(entity).unwrap().x += 1;
+    }
+
+    fn ack(world: &mut World, seq: u32) {
+        world.write_resource::<ClientViews>().0.get_mut(&CLIENT).unwrap().ack(seq);
+    }
+
+    #[test]
+    fn deltas_build_on_the_latest_acked_or_full_snapshot() {
+        let (mut world, mut system, player) = world(vec![]);
+
+        let full = tick(&mut world, &mut system).unwrap();
+        assert_eq!(full.baseline, None);
+
+        // a full snapshot is built upon straight away, without waiting for its ack
+        step(&mut world, player);
+        let delta = tick(&mut world, &mut system).unwrap();
+        assert_eq!(delta.baseline, Some (full.seq));
+
+        step(&mut world, player);
+        ack(&mut world, delta.seq);
+        let next = tick(&mut world, &mut system).unwrap();
+        assert_eq!(next.baseline, Some (delta.seq));
+
+        // acks arriving out of order never move the baseline back
+        ack(&mut world, full.seq);
+        step(&mut world, player);
+        assert_eq!(tick(&mut world, &mut system).unwrap().baseline, Some (delta.seq));
+    }
+
+    #[test]
+    fn clients_behind_the_history_get_a_full_snapshot() {
+        let (mut world, mut system, player) = world(vec![]);
+        let full = tick(&mut world, &mut system).unwrap();
+
+        // the client never acks, so every delta is built against the first snapshot until it's forgotten
+        let mut snapshots = vec![];
+        for _ in 0..ReplicationLog::MAX_HISTORY + 1 {
+            step(&mut world, player);
+            snapshots.push(tick(&mut world, &mut system).unwrap());
+        }
+        let (last, deltas) = snapshots.split_last().unwrap();
+        assert!(deltas.iter().all(|snapshot| snapshot.baseline == Some (full.seq)));
+        assert_eq!(last.baseline, None);
+        assert!(last.entities.iter().any(|(eid, _)| *eid == player.id()));
+    }
+
+    #[test]
+    fn entities_coming_back_into_view_are_sent_in_full() {
+        let spot = Position { x: 3, y: 0, floor: 0 };
+        let (mut world, mut system, player) = world(vec![spot.clone()]);
+        let other = monster(&mut world, spot.clone());
+
+        let full = tick(&mut world, &mut system).unwrap();
+        assert!(full.entities.iter().any(|(eid, _)| *eid == other.id()));
+        ack(&mut world, full.seq);
+
+        world.write_storage::<Viewshed>().get_mut(player).unwrap().visible.clear();
+        let gone = tick(&mut world, &mut system).unwrap();
+        assert_eq!(gone.removed, vec![other.id()]);
+        ack(&mut world, gone.seq);
+
+        // nothing about it changed while it was out of view, but the client has forgotten it
+        world.write_storage::<Viewshed>().get_mut(player).unwrap().visible.insert(spot);
+        let back = tick(&mut world, &mut system).unwrap();
+        let (_, components) = back.entities.iter().find(|(eid, _)| *eid == other.id()).unwrap();
+        assert_eq!(components.len(), 3);
+        assert!(components.iter().any(|c| matches!(c, ServerComponentKind::Position(_))));
+        assert!(components.iter().any(|c| matches!(c, ServerComponentKind::GameObjectDetails(_))));
+        assert!(components.iter().any(|c| matches!(c, ServerComponentKind::Health(_))));
+    }
+}
@@ -1,7 +1,48 @@
 
-use std::sync::mpsc;
+use std::fs;
 
-use crate::messages::Message;
+use anyhow::Result;
+use rand_chacha::ChaCha8Rng;
+use serde::Deserialize;
 
+use crate::{components::Action, queue::MessageQueue};
 
-pub struct ServerTx(pub mpsc::Sender<Message>);
+
+/// Lets systems send messages to the server, through the same queue as connections.
+pub struct ServerTx(pub MessageQueue);
+
+/// Every roll of the dice during play, seeded with the dungeon's seed.
+pub struct GameRng(pub ChaCha8Rng);
+
+/// How much `Energy` each kind of action takes, from `content/actions.json`.
+/// Bumping into something hostile attacks it instead of moving, and costs `melee` instead of `step`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ActionCosts {
+    #[serde(rename = "move")]
+    pub step: i32,
+    pub use_ladder: i32,
+    pub wait: i32,
+    pub melee: i32,
+    pub ranged: i32,
+    pub pick_up: i32,
+    pub drop: i32
+}
+
+impl ActionCosts {
+    pub fn load(path: &str) -> Result<Self> {
+        Ok (serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    pub fn cost(&self, action: &Action) -> i32 {
+        match action {
+            Action::Move(_) => self.step,
+            Action::UseLadder => self.use_ladder,
+            Action::Fire(_) => self.ranged,
+            Action::Travel(_) => 0,
+            Action::PickUp => self.pick_up,
+            Action::Drop(_) => self.drop,
+            Action::Wait => self.wait
+        }
+    }
+}
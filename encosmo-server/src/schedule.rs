@@ -0,0 +1,113 @@
+// The order systems run in each tick. Actors get a fresh round to act in for every `Energy::TO_ACT` they can have
+// built up, so the fastest of them can act more than once a tick.
+
+use specs::prelude::*;
+
+use crate::{components::Energy, replication::ReplicationSystem, systems::*};
+
+pub struct Schedule {
+    start: Dispatcher<'static, 'static>,    // once a tick, before anyone acts
+    round: Dispatcher<'static, 'static>,    // once for every action the fastest actor may take this tick
+    end: Dispatcher<'static, 'static>       // once a tick, after everyone has acted
+}
+
+impl Schedule {
+    /// As many actions as anyone can build up the energy for in a tick.
+    pub const ROUNDS: i32 = Energy::MAX / Energy::TO_ACT;
+
+    pub fn new() -> Self {
+        let start = DispatcherBuilder::new()
+            .with_thread_local(EnergySystem)
+            .build();
+        let round = DispatcherBuilder::new()
+            .with_thread_local(PathCacheSystem)
+            .with_thread_local(InputSystem)
+            .with_thread_local(TravelSystem)
+            .with_thread_local(MonsterAiSystem)
+            .with_thread_local(MoveSystem)
+            .with_thread_local(CombatSystem)
+            .with_thread_local(DamageSystem)
+            .with_thread_local(VisibilitySystem)
+            .build();
+        // replication runs last so it sees everything the other systems did this tick
+        let end = DispatcherBuilder::new()
+            .with_thread_local(ReplicationSystem::default())
+            .build();
+        Schedule { start, round, end }
+    }
+
+    pub fn setup(&mut self, world: &mut World) {
+        self.start.setup(world);
+        self.round.setup(world);
+        self.end.setup(world);
+    }
+
+    pub fn run(&mut self, world: &mut World) {
+        self.start.dispatch(world);
+        for _ in 0..Self::ROUNDS {
+            self.round.dispatch(world);
+            // whoever died this round is gone before the next
+            world.maintain();
+        }
+        self.end.dispatch(world);
+        world.maintain();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use encosmo_shared::server_components::Position;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    use super::*;
+    use crate::{components::{Behaviour, CombatStats, Speed}, dungeon::Dungeon, entities::create_monster, monsters::Archetype, queue::MessageQueue, resources::{ActionCosts, GameRng, ServerTx}};
+
+    fn wanderer(speed: i32) -> Archetype {
+        Archetype {
+            name: "Wanderer".to_owned(),
+            description: String::new(),
+            sprite: String::new(),
+            floors: (0, 0),
+            health: 10,
+            defence: 0,
+            speed,
+            view_range: 8,
+            combat: CombatStats { melee: 1, ranged: 0, range: 0, accuracy: 50, evasion: 0 },
+            behaviour: Behaviour { wander: true, ..Default::default() }
+        }
+    }
+
+    #[test]
+    fn fast_actors_act_twice_a_tick() {
+        let mut world = World::new();
+        world.insert(ServerTx(MessageQueue::new()));
+        world.insert(ActionCosts::load("content/actions.json").unwrap());
+        world.insert(GameRng(ChaCha8Rng::seed_from_u64(1)));
+        let dungeon = Dungeon::generate(1);
+        // on floors of their own, so they never get in each other's way
+        let (fast_start, normal_start) = (dungeon.floor_entrance(0).clone(), dungeon.floor_entrance(1).clone());
+        world.insert(dungeon);
+        let mut schedule = Schedule::new();
+        schedule.setup(&mut world);
+
+        let fast = create_monster(&mut world, &wanderer(2 * Speed::NORMAL), fast_start);
+        let normal = create_monster(&mut world, &wanderer(Speed::NORMAL), normal_start);
+        let mut moves = world.write_storage::<Position>().register_reader();
+        world.write_storage::<Position>().channel().read(&mut moves).for_each(drop);
+
+        const TICKS: usize = 20;
+        let (mut fast_moves, mut normal_moves) = (0, 0);
+        for _ in 0..TICKS {
+            schedule.run(&mut world);
+            for event in world.read_storage::<Position>().channel().read(&mut moves) {
+                if let ComponentEvent::Inserted(id) | ComponentEvent::Modified(id) = event {
+                    fast_moves += (*id == fast.id()) as usize;
+                    normal_moves += (*id == normal.id()) as usize;
+                }
+            }
+        }
+        assert_eq!(normal_moves, TICKS);
+        assert_eq!(fast_moves, 2 * TICKS);
+    }
+}
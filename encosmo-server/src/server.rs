@@ -2,40 +2,49 @@ use std::{collections::HashMap, sync::Arc, time::Duration};
 use anyhow::Result;
 
 use bimap::BiMap;
-use encosmo_shared::{server_components::*, Packet};
-use specs::{prelude::*, storage::AccessMut};
-use tokio::{net::TcpListener, spawn, sync::{broadcast, mpsc, Mutex}, time::{sleep, Instant}};
+use encosmo_shared::{codec::FrameDecoder, server_components::*, Packet};
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use specs::prelude::*;
+use tokio::{net::{TcpListener, TcpStream}, spawn, sync::{broadcast, mpsc, Mutex}, time::{sleep, Instant}};
 use uuid::Uuid;
 
-use crate::{connection::Connection, entities::create_player, messages::Message, resources::ServerTx, systems::*};
+use crate::{components::{Action, BlocksTile, Carried, CombatStats, Energy, Explored, Monster, PartyMember, PlayerInputs, Speed, SufferDamage, Travel, Viewshed, WantsToAttack}, connection::{handshake, Connection}, dungeon::Dungeon, entities::{self, create_player}, items::{self, ItemKinds}, messages::Message, monsters::{self, Bestiary}, party, queue::MessageQueue, replication::*, resources::{ActionCosts, GameRng, ServerTx}, schedule::Schedule};
+
+/// When the world moves on, i.e. when the systems are run.
+#[derive(Debug, Clone, Copy)]
+pub enum TickMode {
+    RealTime,                       // every tick, whether players have acted or not
+    Turns { timeout: Duration }     // once every player has an action queued, idle players are passed over after `timeout`
+}
 
 pub struct Server {
     connections: Arc<Mutex<HashMap<Uuid, mpsc::Sender<Message>>>>,
     tick_rate: u8,
+    seed: u64,
+    mode: TickMode,
+    last_turn: Instant,
+    joined: bool,           // someone has joined since the last turn
     broadcast_tx: broadcast::Sender<Message>,
-    server_tx: mpsc::Sender<Message>,
-    server_rx: mpsc::Receiver<Message>,
+    queue: MessageQueue,    // shared by connections and systems, so messages are handled in the order they happened
     world: Arc<Mutex<World>>,
     player_entities: Arc<Mutex<BiMap<Uuid, u32>>>,
-    systems_tx: std::sync::mpsc::Sender<Message>,
-    systems_rx: std::sync::mpsc::Receiver<Message>,
 }
 
 impl Server {
-    pub fn new(tick_rate: u8) -> Self {
+    pub fn new(tick_rate: u8, seed: u64, mode: TickMode) -> Self {
         let (broadcast_tx, _) = broadcast::channel(100);
-        let (server_tx, server_rx) = mpsc::channel(100);
-        let (systems_tx, systems_rx) = std::sync::mpsc::channel();
         Server {
             connections: Arc::new(Mutex::new(HashMap::new())),
             tick_rate,
+            seed,
+            mode,
+            last_turn: Instant::now(),
+            joined: false,
             broadcast_tx,
-            server_tx,
-            server_rx,
+            queue: MessageQueue::new(),
             world: Arc::new(Mutex::new(World::new())),
-            player_entities: Arc::new(Mutex::new(BiMap::default())),
-            systems_tx,
-            systems_rx
+            player_entities: Arc::new(Mutex::new(BiMap::default()))
         }
     }
 
@@ -43,6 +52,8 @@ impl Server {
         let listener = TcpListener::bind(("0.0.0.0", port)).await?;
         log::info!("SERVER: listening on port {}", port);
     
+        let mut schedule = Schedule::new();
+
         // set up ECS
         {
             let mut lock = self.world.lock().await;
@@ -50,15 +61,37 @@ impl Server {
             lock.register::<Translate>();
             lock.register::<PlayerDetails>();
             lock.register::<GameObjectDetails>();
-            lock.insert(ServerTx(self.systems_tx.clone()));
+            lock.register::<BlocksTile>();
+            lock.register::<PlayerInputs>();
+            lock.register::<Viewshed>();
+            lock.register::<PartyMember>();
+            lock.register::<Speed>();
+            lock.register::<Energy>();
+            lock.register::<Health>();
+            lock.register::<Defence>();
+            lock.register::<SufferDamage>();
+            lock.register::<CombatStats>();
+            lock.register::<WantsToAttack>();
+            lock.register::<Monster>();
+            lock.register::<Travel>();
+            lock.register::<Explored>();
+            lock.register::<Item>();
+            lock.register::<Carried>();
+            lock.insert(GameRng(ChaCha8Rng::seed_from_u64(self.seed)));
+            lock.insert(ServerTx(self.queue.clone()));
+            lock.insert(ActionCosts::load("content/actions.json")?);
+            lock.insert(Dungeon::generate(self.seed));
+            log::info!("SERVER: generated dungeon from seed {}", self.seed);
+            log::info!("SERVER: running in {:?} mode", self.mode);
+            schedule.setup(&mut lock);
+            lock.insert(Bestiary::load("content/monsters.json")?);
+            monsters::populate(&mut lock);
+            lock.insert(ItemKinds::load("content/items.json")?);
+            items::scatter(&mut lock);
         }
-
-        let mut dispatcher = DispatcherBuilder::new()
-            .with_thread_local(MoveSystem)
-            .build();
     
         let broadcast_tx = self.broadcast_tx.clone();
-        let server_tx = self.server_tx.clone();
+        let queue = self.queue.clone();
         let connections = self.connections.clone();
         let world_cpy = self.world.clone();
         let player_entities_cpy = self.player_entities.clone();
@@ -67,10 +100,24 @@ impl Server {
         spawn(async move {
             loop {
                 let broadcast_rx = broadcast_tx.subscribe();
-                match accept_connection(broadcast_rx, server_tx.clone(), connections.clone(), &listener, world_cpy.clone(), player_entities_cpy.clone()).await {
-                    Err (e) => log::error!("Error accepting new connection: {}", e),
-                    _ => {}
-                }
+                let stream = match listener.accept().await {
+                    Err (e) => {
+                        log::error!("Error accepting new connection: {}", e);
+                        continue;
+                    },
+                    Ok ((stream, _)) => stream
+                };
+                let queue = queue.clone();
+                let connections = connections.clone();
+                let world_cpy = world_cpy.clone();
+                let player_entities_cpy = player_entities_cpy.clone();
+
+                // handshake in its own task so a slow client can't hold up everyone else joining
+                spawn(async move {
+                    if let Err (e) = accept_connection(stream, broadcast_rx, queue, connections, world_cpy, player_entities_cpy).await {
+                        log::error!("Error accepting new connection: {}", e);
+                    }
+                });
             }
         });
 
@@ -81,7 +128,7 @@ impl Server {
             // restart timer
             let start_time = Instant::now();
 
-            self.tick(&mut dispatcher).await?;
+            self.tick(&mut schedule).await?;
 
             // get elapsed time
             let elapsed_time = start_time.elapsed();
@@ -96,25 +143,21 @@ impl Server {
         }
     }
 
-    async fn tick(&mut self, dispatcher: &mut Dispatcher<'_, '_>) -> Result<()> {
+    async fn tick(&mut self, schedule: &mut Schedule) -> Result<()> {
         log::debug!("tick");
 
-        // TODO: these will not be in order!! due to sync vs async message queues
-        // check if there are any messages to process
-        while let Ok (msg) = self.server_rx.try_recv() {
-            self.process_message(msg).await?;
-        }
-
-        // also check messages sent from systems bc they can't share the same queue :(
-        while let Ok (msg) = self.systems_rx.try_recv() {
-            self.process_message(msg).await?;
+        // process everything that happened since last tick, from connections and systems alike, in order
+        for envelope in self.queue.drain() {
+            log::trace!("processing message #{} queued {:?} ago", envelope.seq, envelope.queued_at.elapsed());
+            self.process_message(envelope.msg).await?;
         }
     
-        // run all our systems
-        {
+        // run all our systems, unless we're still waiting on someone's turn
+        if self.turn_ready().await {
             let mut lock = self.world.lock().await;
-            dispatcher.dispatch(&lock);
-            lock.maintain();
+            schedule.run(&mut lock);
+            self.last_turn = Instant::now();
+            self.joined = false;
         }
         
         // send all packets from each connection's outbox
@@ -122,21 +165,75 @@ impl Server {
         Ok (())
     }
 
+    /// Whether the world should move on this tick. In turn mode that's once every player has an action queued or is travelling,
+    /// once the turn has timed out, or when someone has joined, so they're shown where they are before they're waited on.
+    async fn turn_ready(&self) -> bool {
+        let TickMode::Turns { timeout } = self.mode else {
+            return true;
+        };
+        if self.joined {
+            return true;
+        }
+        if self.last_turn.elapsed() >= timeout {
+            log::debug!("turn timed out, passing over idle players");
+            return true;
+        }
+
+        // players who are downed or won't have the energy to act this turn aren't waited on
+        let world = self.world.lock().await;
+        let (inputs, energy, speed, health) = (world.read_storage::<PlayerInputs>(), world.read_storage::<Energy>(), world.read_storage::<Speed>(), world.read_storage::<Health>());
+        let travel = world.read_storage::<Travel>();
+        let mut players = (&inputs, &energy, &speed, health.maybe(), travel.maybe()).join().peekable();
+        players.peek().is_some() && players
+            .filter(|(_, energy, speed, health, _)| energy.0 + speed.0 >= Energy::TO_ACT && !health.is_some_and(Health::is_down))
+            .all(|(inputs, .., travel)| !inputs.pending.is_empty() || travel.is_some())
+    }
+
     async fn process_message(&mut self, msg: Message) -> Result<()> {
         match msg {
-            Message::PlayerConnected(_) | Message::PlayerDisconnected(_) => {
+            Message::PlayerConnected(_) => {
+                self.joined = true;
                 self.broadcast_tx.send(msg)?;
             },
-            Message::Packet(Packet::UpdateComponent(eid, ref comp)) => {
-                match comp {
-                    ServerComponentKind::Translate(t) => {
-                        self.update_component(eid, t).await?;
-                    },
-                    _ => log::warn!("Client {} attempted to update component they cannot: {:?}", eid, comp)
+            Message::PlayerDisconnected(id) => {
+                self.despawn_player(id).await?;
+                self.broadcast_tx.send(msg)?;
+            },
+            Message::Input(eid, seq, action) => self.queue_input(eid, seq, action).await,
+            Message::SnapshotAck(id, seq) => {
+                let world = self.world.lock().await;
+                let mut views = world.write_resource::<ClientViews>();
+                if let Some (view) = views.0.get_mut(&id) {
+                    view.ack(seq);
+                }
+            },
+            Message::JoinParty(id) => {
+                let Some (eid) = self.player_entities.lock().await.get_by_left(&id).copied() else {
+                    return Ok (());
+                };
+                let mut world = self.world.lock().await;
+                let entity = world.entities().entity(eid);
+                if !party::join(&mut world, entity) {
+                    // they're told who is in the party instead, which doesn't include them
+                    log::info!("Player {} couldn't join the party, it's full", id);
+                    let members = party::members(&world);
+                    drop(world);
+                    self.send_packet_to(id, Packet::PartyMembers(members)).await?;
                 }
             },
+            Message::LeaveParty(id) => {
+                let Some (eid) = self.player_entities.lock().await.get_by_left(&id).copied() else {
+                    return Ok (());
+                };
+                let mut world = self.world.lock().await;
+                let entity = world.entities().entity(eid);
+                party::leave(&mut world, entity);
+            },
             Message::BroadcastPacket(p) => {
                 self.broadcast_tx.send(Message::SendPacket(p))?;
+            },
+            Message::SendPacketTo(id, p) => {
+                self.send_packet_to(id, p).await?;
             }
             _ => {}
         }
@@ -144,57 +241,93 @@ impl Server {
         Ok (())
     }
 
-    async fn update_component<T>(&mut self, eid: u32, new_component: &T) -> Result<()> 
-    where
-        T: UpdatableComponent,
-    {
-        let entities = self.player_entities.lock().await;
-        let res = entities.get_by_right(&eid);
-        if res.is_none() {
-            log::error!("Attempting to update entity that doesn't exist");
-            return Ok(());
+    async fn send_packet_to(&self, id: Uuid, p: Packet) -> Result<()> {
+        let connection = self.connections.lock().await.get(&id).cloned();
+        if let Some (connection) = connection {
+            // the connection may have closed since the packet was queued, it's cleaned up on PlayerDisconnected
+            if connection.send(Message::SendPacket(p)).await.is_err() {
+                log::debug!("Dropping packet for {}, their connection has closed", id);
+            }
         }
-        let entity = self.world.lock().await.entities().entity(eid);
+        Ok (())
+    }
 
+    /// Clients only get to ask for things to happen; whether and how they do is up to the server.
+    /// Here we only queue the request, `InputSystem` and the systems after it decide what comes of it.
+    async fn queue_input(&mut self, eid: u32, seq: u32, action: Action) {
         let world = self.world.lock().await;
-        let mut storage = world.write_storage::<T>();
-
-        if let Some(mut storage) = storage.get_mut(entity) {
-            let existing_component = storage.access_mut();
-            *existing_component = (new_component).clone();
-            log::info!("Updated component for entity: {}", eid);
-        } else {
-            log::error!("Entity {:?} does not have the specified component!", entity);
+        let entity = world.entities().entity(eid);
+        let mut inputs = world.write_storage::<PlayerInputs>();
+        let Some (inputs) = inputs.get_mut(entity) else {
+            log::warn!("Entity {} has no player inputs", eid);
+            return;
+        };
+
+        if inputs.pending.len() >= PlayerInputs::MAX_PENDING {
+            // not acknowledged, that would be out of order with the inputs still queued. it's the newest the
+            // client has sent, so the ack for whichever input comes next covers it and puts the client right
+            log::warn!("Entity {} has too many inputs queued, dropping input {}", eid, seq);
+            return;
         }
+        inputs.pending.push_back((seq, action));
+    }
+
+    /// Removes every trace of a player that has left. Everyone else hears their entity is gone from replication.
+    async fn despawn_player(&mut self, id: Uuid) -> Result<()> {
+        self.connections.lock().await.remove(&id);
 
-        Ok(())
+        let Some ((_, eid)) = self.player_entities.lock().await.remove_by_left(&id) else {
+            log::warn!("Player {} disconnected but had no entity", id);
+            return Ok (());
+        };
+
+        {
+            let mut world = self.world.lock().await;
+            world.write_resource::<ClientViews>().0.remove(&id);
+            let entity = world.entities().entity(eid);
+            let was_member = world.read_storage::<PartyMember>().contains(entity);
+            entities::drop_carried(&world, entity);
+            world.delete_entity(entity)?;
+            if was_member {
+                party::party_changed(&world, None);
+            }
+        }
+
+        log::info!("Despawned entity {} of player {}", eid, id);
+        Ok (())
     }
 }
 
 async fn accept_connection(
+    stream: TcpStream,
     broadcast_rx: broadcast::Receiver<Message>,
-    server_tx: mpsc::Sender<Message>,
+    queue: MessageQueue,
     connections: Arc<Mutex<HashMap<Uuid, mpsc::Sender<Message>>>>,
-    listener: &TcpListener,
     world: Arc<Mutex<World>>,
     player_entities: Arc<Mutex<BiMap<Uuid, u32>>>
 ) -> Result<()> {
-    let (stream, _) = listener.accept().await?;
-    let (client_rx, client_tx) = stream.into_split();
+    let (mut client_rx, mut client_tx) = stream.into_split();
+
+    // nothing about the player exists until the client has proven it speaks our protocol
+    let mut decoder = FrameDecoder::new();
+    let Some (capabilities) = handshake(&mut client_rx, &mut client_tx, &mut decoder).await? else {
+        return Ok (());
+    };
+
     let id = Uuid::new_v4();
     let (conn_tx, conn_rx) = mpsc::channel(100);
-    let chan = (server_tx.clone(), conn_rx);
+    let chan = (queue.clone(), conn_rx);
 
     // limiting lifetime of each lock
     {
         let mut lock = connections.lock().await;
-        lock.insert(id, conn_tx);
+        lock.insert(id, conn_tx.clone());
     }
 
     let entity_id: u32;
 
     {
-        // TODO: we are adding player to world here, but never deleting them.
+        // removed again in `Server::despawn_player` once the connection closes
         let mut lock = world.lock().await;
         let player_entity = create_player(&mut lock, id);
         entity_id = player_entity.id();
@@ -202,28 +335,31 @@ async fn accept_connection(
             let mut lock = player_entities.lock().await;
             lock.insert(id, player_entity.id());
         }
+
+        // the client is sent everything around them on the next tick, then only what changes
+        lock.write_resource::<ClientViews>().0.insert(id, ClientView::new(player_entity));
+
+        // sent again whenever they change floors
+        let floor = lock.read_storage::<Position>().get(player_entity).map_or(0, |pos| pos.floor);
+        let map = lock.read_resource::<Dungeon>().floor(floor).clone();
+        conn_tx.send(Message::SendPacket(Packet::FloorMap(map))).await?;
     }
     
-    // clone
-    let _connections = connections.clone();
-
     let mut connection = Connection::new(id, entity_id, client_tx, chan, broadcast_rx);
 
+    let disconnect_tx = queue.clone();
     spawn(async move {
-        match connection.start(client_rx).await {
+        match connection.start(client_rx, decoder).await {
             Err (e) => log::error!("Client {} disconnected with error {}", id, e),
             _ => log::info!("Player {} has disconnected gracefully.", id)
         }
-        // connection has finished
-        _connections.lock().await.remove(&id);
+        // connection has finished however it went, the server cleans up after it on PlayerDisconnected
+        disconnect_tx.push(Message::PlayerDisconnected(id));
     });
 
-    // TODO:
-    // send the world as we know it up to this point
-
-    server_tx.send(Message::PlayerConnected(id)).await?;
-    server_tx.send(Message::BroadcastPacket(Packet::PlayerEntityId(id, entity_id))).await?;
-    log::info!("New connection: {}", id);
+    queue.push(Message::PlayerConnected(id));
+    queue.push(Message::BroadcastPacket(Packet::PlayerEntityId(id, entity_id)));
+    log::info!("New connection: {} with capabilities {:?}", id, capabilities);
 
     Ok (())
 }
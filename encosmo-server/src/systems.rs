@@ -1,26 +1,628 @@
-use specs::prelude::*;
-use encosmo_shared::{server_components::*, Packet};
+use std::{collections::{HashMap, HashSet}, ops::Deref};
 
-use crate::{messages::Message, resources::ServerTx};
+use rand::{seq::SliceRandom, Rng};
+use specs::{prelude::*, storage::MaskedStorage};
+use encosmo_shared::{server_components::*, AttackReport, Packet, TravelStop};
 
+use crate::{components::{Action, BlocksTile, Carried, CombatStats, Destination, Energy, Explored, Monster, PartyMember, PlayerInputs, Speed, SufferDamage, Travel, Viewshed, WantsToAttack}, dungeon::{nearest_free_tile, Dungeon}, entities::occupied_positions, fov::field_of_view, messages::Message, party::shared_vision, pathfinding::{DijkstraMap, PathCache}, replication::ClientViews, resources::{ActionCosts, GameRng, ServerTx}};
+
+/// Gives every actor the energy its speed earns it this tick. Runs first, so anyone with enough can act straight away.
+/// Nobody builds up more than a tick's worth, so standing around idle doesn't save up actions for later.
+pub struct EnergySystem;
+
+impl<'a> System<'a> for EnergySystem {
+    type SystemData = (WriteStorage<'a, Energy>, ReadStorage<'a, Speed>);
+
+    fn run(&mut self, (mut energy, speed): Self::SystemData) {
+        for (energy, speed) in (&mut energy, &speed).join() {
+            energy.0 = (energy.0 + speed.0).min(speed.0.clamp(Energy::TO_ACT, Energy::MAX));
+        }
+    }
+}
+
+/// Carries out the oldest of each player's queued inputs, at most one per round and only when the player has the energy to.
+/// Moves are handed on to `MoveSystem`, shots to `CombatSystem`, ladders are climbed and items picked up or dropped
+/// straight away, and waiting does nothing.
+/// Travelling is left to `TravelSystem`, and any other input puts a stop to it.
+/// Inputs that can't be carried out are still taken off the queue, so the player's inputs
+/// are acknowledged in the order they were sent.
+pub struct InputSystem;
+
+impl<'a> System<'a> for InputSystem {
+    type SystemData = (
+        Entities<'a>,
+        WriteStorage<'a, PlayerInputs>,
+        WriteStorage<'a, Energy>,
+        WriteStorage<'a, Translate>,
+        WriteStorage<'a, Position>,
+        WriteStorage<'a, WantsToAttack>,
+        WriteStorage<'a, Travel>,
+        WriteStorage<'a, Carried>,
+        ReadStorage<'a, Item>,
+        ReadStorage<'a, GameObjectDetails>,
+        ReadStorage<'a, BlocksTile>,
+        ReadStorage<'a, PlayerDetails>,
+        ReadStorage<'a, Health>,
+        ReadStorage<'a, CombatStats>,
+        ReadStorage<'a, Viewshed>,
+        ReadExpect<'a, Dungeon>,
+        ReadExpect<'a, ActionCosts>,
+        ReadExpect<'a, ServerTx>
+    );
+
+    fn run(&mut self, (entities, mut inputs, mut energy, mut trans, mut pos, mut attacks, mut travel, mut carried, items, details, blockers, players, health, stats, viewsheds, dungeon, costs, res): Self::SystemData) {
+        for (entity, inputs, energy, trans) in (&entities, &mut inputs, &mut energy, &mut trans).join() {
+            if !trans.is_zero() || inputs.in_flight.is_some() || !energy.can_act() || health.get(entity).is_some_and(Health::is_down) {
+                continue;
+            }
+            let Some ((seq, action)) = inputs.pending.pop_front() else {
+                continue;
+            };
+            inputs.in_flight = Some (seq);
+            // paid whether or not the action works out, walking into a wall still wastes time
+            energy.0 -= costs.cost(&action);
+            // doing anything else stops the player travelling
+            travel.remove(entity);
+
+            match action {
+                Action::Move(t) if t.is_single_step() => *trans = t,
+                Action::Move(t) => log::warn!("Entity {} attempted to move more than one tile: {:?}", entity.id(), t),
+                Action::Wait => (),
+                Action::Travel(Destination::To(goal)) if !dungeon.is_walkable(&goal) => log::warn!("Entity {} attempted to travel somewhere it can't stand: {:?}", entity.id(), goal),
+                Action::Travel(to) => {
+                    let seen = visible_hostiles(entity, &entities, &pos, &players, &health, &viewsheds);
+                    _ = travel.insert(entity, Travel { to, seen });
+                },
+                Action::Fire(target) => {
+                    let target = entities.entity(target);
+                    let shot = match entities.is_alive(target) {
+                        true => check_shot(entity, target, &pos, &players, &health, &stats, &viewsheds),
+                        false => Err ("it doesn't exist")
+                    };
+                    match shot {
+                        Ok (()) => _ = attacks.insert(entity, WantsToAttack { target, ranged: true }),
+                        Err (reason) => log::warn!("Entity {} attempted to shoot at entity {} but {}", entity.id(), target.id(), reason)
+                    }
+                },
+                Action::PickUp => {
+                    let Some (here) = pos.get(entity).cloned() else {
+                        continue;
+                    };
+                    let Some ((item, ..)) = (&entities, &items, &pos).join().find(|(_, _, p)| **p == here) else {
+                        log::warn!("Entity {} attempted to pick up an item where there is none: {:?}", entity.id(), here);
+                        continue;
+                    };
+                    if (&carried).join().filter(|carried| carried.by == entity).count() >= Carried::MAX_ITEMS {
+                        log::warn!("Entity {} attempted to pick up entity {} but its backpack is full", entity.id(), item.id());
+                        continue;
+                    }
+                    // out of sight of everyone until it's dropped, replication despawns it on clients
+                    pos.remove(item);
+                    _ = carried.insert(item, Carried { by: entity });
+
+                    if let Some (player) = players.get(entity) {
+                        res.0.push(Message::SendPacketTo(player.0, inventory(entity, &entities, &carried, &details, &items)));
+                    }
+                },
+                Action::Drop(item) => {
+                    let item = entities.entity(item);
+                    let ours = entities.is_alive(item) && carried.get(item).is_some_and(|carried| carried.by == entity);
+                    let (true, Some (here)) = (ours, pos.get(entity).cloned()) else {
+                        log::warn!("Entity {} attempted to drop entity {} but isn't carrying it", entity.id(), item.id());
+                        continue;
+                    };
+                    carried.remove(item);
+                    _ = pos.insert(item, here);
+
+                    if let Some (player) = players.get(entity) {
+                        res.0.push(Message::SendPacketTo(player.0, inventory(entity, &entities, &carried, &details, &items)));
+                    }
+                },
+                Action::UseLadder => {
+                    let Some (current) = pos.get(entity).cloned() else {
+                        continue;
+                    };
+                    let Some (destination) = dungeon.climb(&current) else {
+                        log::warn!("Entity {} attempted to use a ladder where there is none: {:?}", entity.id(), current);
+                        continue;
+                    };
+
+                    // whoever is standing at the foot of the ladder gets stepped around
+                    let occupied = occupied_positions(&pos, &blockers);
+                    let Some (arrival) = nearest_free_tile(&dungeon, &destination, &occupied) else {
+                        log::warn!("Entity {} found no room to arrive at {:?}", entity.id(), destination);
+                        continue;
+                    };
+                    _ = pos.insert(entity, arrival.clone());
+
+                    if let Some (player) = players.get(entity) {
+                        res.0.push(Message::SendPacketTo(player.0, Packet::FloorMap(dungeon.floor(arrival.floor).clone())));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Everything `owner` is carrying, as told to its player.
+fn inventory(
+    owner: Entity,
+    entities: &Entities,
+    carried: &WriteStorage<Carried>,
+    details: &ReadStorage<GameObjectDetails>,
+    items: &ReadStorage<Item>
+) -> Packet {
+    let carrying = (entities, carried, details, items).join()
+        .filter(|(_, carried, ..)| carried.by == owner)
+        .map(|(item, _, details, pile)| (item.id(), details.clone(), pile.clone()))
+        .collect();
+    Packet::Inventory(carrying)
+}
+
+/// Players and everything else are on opposite sides, and only fight each other.
+fn hostile(players: &ReadStorage<PlayerDetails>, a: Entity, b: Entity) -> bool {
+    players.contains(a) != players.contains(b)
+}
+
+/// The hostiles `viewer` can see that are still standing.
+fn visible_hostiles<D: Deref<Target = MaskedStorage<Position>>>(
+    viewer: Entity,
+    entities: &Entities,
+    pos: &Storage<Position, D>,
+    players: &ReadStorage<PlayerDetails>,
+    health: &ReadStorage<Health>,
+    viewsheds: &ReadStorage<Viewshed>
+) -> HashSet<Entity> {
+    let Some (viewshed) = viewsheds.get(viewer) else {
+        return HashSet::new();
+    };
+    (entities, pos, health).join()
+        .filter(|(other, p, health)| hostile(players, viewer, *other) && !health.is_down() && viewshed.visible.contains(p))
+        .map(|(other, ..)| other)
+        .collect()
+}
+
+/// Why `shooter` can't shoot at `target` right now, if it can't.
+fn check_shot<D: Deref<Target = MaskedStorage<Position>>>(
+    shooter: Entity,
+    target: Entity,
+    pos: &Storage<Position, D>,
+    players: &ReadStorage<PlayerDetails>,
+    health: &ReadStorage<Health>,
+    stats: &ReadStorage<CombatStats>,
+    viewsheds: &ReadStorage<Viewshed>
+) -> Result<(), &'static str> {
+    if !hostile(players, shooter, target) {
+        return Err ("it isn't hostile");
+    }
+    if health.get(target).is_none_or(Health::is_down) {
+        return Err ("it can't be hurt");
+    }
+    let Some (stats) = stats.get(shooter).filter(|stats| stats.ranged > 0) else {
+        return Err ("it has nothing to shoot with");
+    };
+    let (Some (from), Some (to)) = (pos.get(shooter), pos.get(target)) else {
+        return Err ("one of them isn't anywhere");
+    };
+    if distance(from, to) > stats.range {
+        return Err ("it's out of range");
+    }
+    if !viewsheds.get(shooter).is_some_and(|viewshed| viewshed.visible.contains(to)) {
+        return Err ("it can't see it");
+    }
+    Ok (())
+}
+
+/// Tiles apart, counting diagonal steps as one.
+fn distance(a: &Position, b: &Position) -> i32 {
+    (a.x - b.x).abs().max((a.y - b.y).abs())
+}
+
+/// Decides what each monster with the energy to act does, going by its `Behaviour`. In order, it runs from every
+/// player on its floor when badly hurt or when the nearest hostile thing it can see is too close for its liking,
+/// shoots that if it kites, goes after it along the shortest path if it chases, and otherwise wanders or waits.
+/// Moves and melee are handed on to `MoveSystem`, shots to `CombatSystem`. Paths are shared through the `PathCache`.
+pub struct MonsterAiSystem;
+
+impl<'a> System<'a> for MonsterAiSystem {
+    type SystemData = (
+        Entities<'a>,
+        ReadStorage<'a, Monster>,
+        WriteStorage<'a, Energy>,
+        WriteStorage<'a, Translate>,
+        WriteStorage<'a, WantsToAttack>,
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, Viewshed>,
+        ReadStorage<'a, Health>,
+        ReadStorage<'a, CombatStats>,
+        ReadStorage<'a, PlayerDetails>,
+        ReadStorage<'a, BlocksTile>,
+        ReadExpect<'a, Dungeon>,
+        ReadExpect<'a, ActionCosts>,
+        WriteExpect<'a, GameRng>,
+        Write<'a, PathCache>
+    );
+
+    fn run(&mut self, (entities, monsters, mut energy, mut trans, mut attacks, pos, viewsheds, health, stats, players, blockers, dungeon, costs, mut rng, mut cache): Self::SystemData) {
+        let occupied = occupied_positions(&pos, &blockers);
+
+        for (entity, monster, energy, trans, own, viewshed, hp) in (&entities, &monsters, &mut energy, &mut trans, &pos, &viewsheds, &health).join() {
+            if !energy.can_act() || !trans.is_zero() || hp.is_down() {
+                continue;
+            }
+            let behaviour = &monster.behaviour;
+            let target = (&entities, &pos, &health).join()
+                .filter(|(other, p, health)| hostile(&players, entity, *other) && !health.is_down() && viewshed.visible.contains(p))
+                .min_by_key(|(_, p, _)| distance(own, p));
+
+            let action = match target {
+                Some ((target, target_pos, _)) => {
+                    let hurt = behaviour.flee_below.is_some_and(|fraction| (hp.current as f32) < fraction * hp.max as f32);
+                    let too_close = behaviour.kite.is_some_and(|range| distance(own, target_pos) < range);
+                    // stepping onto the target is how melee attacks happen, so it's never in the way
+                    let free = |p: &Position| dungeon.is_walkable(p) && (p == target_pos || !occupied.contains(p));
+                    let away = (hurt || too_close).then(|| {
+                        let players = || (&pos, &players, &health).join()
+                            .filter(|(p, _, health)| p.floor == own.floor && !health.is_down())
+                            .map(|(p, ..)| p.clone())
+                            .collect();
+                        let safety = cache.flee_from_players(&dungeon, own.floor, players);
+                        best_step(own, &free, |p| safety.value(p).unwrap_or(i32::MAX))
+                    });
+                    let shot = || (behaviour.kite.is_some() && check_shot(entity, target, &pos, &players, &health, &stats, &viewsheds).is_ok())
+                        .then_some(Action::Fire(target.id()));
+                    let toward = || {
+                        if !behaviour.chase {
+                            return None;
+                        }
+                        let next = cache.path(&dungeon, &occupied, own, target_pos)?.first()?;
+                        free(next).then(|| Action::Move(Translate { dx: next.x - own.x, dy: next.y - own.y }))
+                    };
+
+                    away.flatten().map(Action::Move).or_else(shot).or_else(toward)
+                },
+                None => None
+            };
+            let wander = || {
+                let steps: Vec<Translate> = neighbours(own)
+                    .filter(|(_, p)| dungeon.is_walkable(p) && !occupied.contains(p))
+                    .map(|(t, _)| t)
+                    .collect();
+                steps.choose(&mut rng.0).cloned().map(Action::Move)
+            };
+            let action = action
+                .or_else(|| behaviour.wander.then(wander).flatten())
+                .unwrap_or(Action::Wait);
+
+            energy.0 -= costs.cost(&action);
+            match action {
+                Action::Move(t) => *trans = t,
+                Action::Fire(target) => _ = attacks.insert(entity, WantsToAttack { target: entities.entity(target), ranged: true }),
+                _ => ()
+            }
+        }
+    }
+}
+
+/// The eight tiles around `pos`, and the step to each.
+fn neighbours(pos: &Position) -> impl Iterator<Item = (Translate, Position)> + '_ {
+    (-1..=1).flat_map(move |dy| (-1..=1).map(move |dx| Translate { dx, dy }))
+        .filter(|t| !t.is_zero())
+        .map(|t| (t.clone(), pos.translated(&t)))
+}
+
+/// The step to whichever free neighbouring tile scores lowest, if it scores lower than staying put.
+fn best_step(from: &Position, free: &impl Fn(&Position) -> bool, score: impl Fn(&Position) -> i32) -> Option<Translate> {
+    neighbours(from)
+        .filter(|(_, p)| free(p))
+        .min_by_key(|(_, p)| score(p))
+        .filter(|(_, p)| score(p) < score(from))
+        .map(|(t, _)| t)
+}
+
+/// Walks travelling players a step at a time, for as long as they have nothing else queued up. A player travelling to
+/// a tile follows the shortest path there, and an exploring one heads for the nearest tile it has seen that is next
+/// to one it hasn't. Travel stops when the player gets there, when it's blocked, or as soon as something hostile
+/// comes into view, and the player is told which.
+pub struct TravelSystem;
+
+impl<'a> System<'a> for TravelSystem {
+    type SystemData = (
+        Entities<'a>,
+        WriteStorage<'a, Travel>,
+        WriteStorage<'a, Energy>,
+        WriteStorage<'a, Translate>,
+        ReadStorage<'a, PlayerInputs>,
+        ReadStorage<'a, Explored>,
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, Viewshed>,
+        ReadStorage<'a, Health>,
+        ReadStorage<'a, PlayerDetails>,
+        ReadStorage<'a, BlocksTile>,
+        ReadExpect<'a, Dungeon>,
+        ReadExpect<'a, ActionCosts>,
+        ReadExpect<'a, ServerTx>,
+        Write<'a, PathCache>
+    );
+
+    fn run(&mut self, (entities, mut travel, mut energy, mut trans, inputs, explored, pos, viewsheds, health, players, blockers, dungeon, costs, res, mut cache): Self::SystemData) {
+        let occupied = occupied_positions(&pos, &blockers);
+        let mut stopped = Vec::new();
+
+        for (entity, travel, energy, trans, inputs, own, player) in (&entities, &mut travel, &mut energy, &mut trans, &inputs, &pos, &players).join() {
+            if health.get(entity).is_none_or(Health::is_down) {
+                stopped.push((entity, None));
+                continue;
+            }
+            // queued inputs come first, and end the travel when they're carried out
+            if !energy.can_act() || !trans.is_zero() || inputs.in_flight.is_some() || !inputs.pending.is_empty() {
+                continue;
+            }
+            // the ones the player already knew about are left to it, and forgotten once they're out of sight
+            let visible = visible_hostiles(entity, &entities, &pos, &players, &health, &viewsheds);
+            let spotted = visible.difference(&travel.seen)
+                .min_by_key(|other| pos.get(**other).map_or(i32::MAX, |p| distance(own, p)))
+                .copied();
+            travel.seen = visible;
+            if let Some (other) = spotted {
+                stopped.push((entity, Some ((player.0, TravelStop::Interrupted(other.id())))));
+                continue;
+            }
+
+            let free = |p: &Position| dungeon.is_walkable(p) && !occupied.contains(p);
+            let step = match &travel.to {
+                Destination::To(goal) if goal == own => Err (TravelStop::Arrived),
+                Destination::To(goal) => match cache.path(&dungeon, &occupied, own, goal).and_then(|path| path.first()) {
+                    Some (next) if free(next) => Ok (Translate { dx: next.x - own.x, dy: next.y - own.y }),
+                    _ => Err (TravelStop::Blocked)
+                },
+                Destination::Explore => {
+                    let seen = explored.get(entity).map(|explored| &explored.0);
+                    let unseen = |p: &Position| seen.is_none_or(|seen| !seen.contains(p));
+                    let frontier: Vec<Position> = seen.into_iter().flatten()
+                        .filter(|p| p.floor == own.floor && dungeon.is_walkable(p))
+                        .filter(|p| neighbours(p).any(|(_, next)| dungeon.is_walkable(&next) && unseen(&next)))
+                        .cloned()
+                        .collect();
+                    let map = DijkstraMap::new(dungeon.floor(own.floor), &frontier);
+                    match map.value(own) {
+                        None => Err (TravelStop::Explored),
+                        Some (_) => best_step(own, &free, |p| map.value(p).unwrap_or(i32::MAX)).ok_or(TravelStop::Blocked)
+                    }
+                }
+            };
+            match step {
+                Ok (t) => {
+                    energy.0 -= costs.step;
+                    *trans = t;
+                },
+                Err (reason) => stopped.push((entity, Some ((player.0, reason))))
+            }
+        }
+
+        for (entity, reason) in stopped {
+            travel.remove(entity);
+            if let Some ((uuid, reason)) = reason {
+                res.0.push(Message::SendPacketTo(uuid, Packet::TravelStopped(reason)));
+            }
+        }
+    }
+}
+
+/// Forgets the last round's paths, everything may have moved since. Runs first in every round.
+pub struct PathCacheSystem;
+
+impl<'a> System<'a> for PathCacheSystem {
+    type SystemData = Write<'a, PathCache>;
+
+    fn run(&mut self, mut cache: Self::SystemData) {
+        cache.clear();
+    }
+}
+
+/// Carries out movement intents, checking them against the map and anything solid standing in the way.
+/// Moving into something hostile attacks it instead, see `CombatSystem`.
+/// Players are told the outcome of their input either way, so they can reconcile their prediction.
 pub struct MoveSystem;
 
 impl<'a> System<'a> for MoveSystem {
-    type SystemData = (Entities<'a>, WriteStorage<'a, Position>, WriteStorage<'a, Translate>, ReadExpect<'a, ServerTx>);
+    type SystemData = (
+        Entities<'a>,
+        WriteStorage<'a, Position>,
+        WriteStorage<'a, Translate>,
+        WriteStorage<'a, PlayerInputs>,
+        WriteStorage<'a, Energy>,
+        WriteStorage<'a, WantsToAttack>,
+        ReadStorage<'a, BlocksTile>,
+        ReadStorage<'a, PlayerDetails>,
+        ReadStorage<'a, Health>,
+        ReadStorage<'a, CombatStats>,
+        ReadExpect<'a, Dungeon>,
+        ReadExpect<'a, ActionCosts>,
+        ReadExpect<'a, ServerTx>
+    );
 
-    fn run(&mut self, (entities, mut pos, mut trans, res): Self::SystemData) {
+    fn run(&mut self, (entities, mut pos, mut trans, mut inputs, mut energy, mut attacks, blockers, players, health, stats, dungeon, costs, res): Self::SystemData) {
         let tx = &res.0;
-        for (entity, pos, trans) in (&entities, &mut pos, &mut trans).join() {
-            if trans.dx != 0 || trans.dy != 0 {
-                pos.x += trans.dx;
-                pos.y += trans.dy;
 
-                // reset translate component after update
-                *trans = Translate::default();
-                let id = entity.id();
+        // kept up to date as things move, so two entities can't step into the same tile in one tick
+        let mut occupied: HashMap<Position, Entity> = (&entities, &pos, &blockers).join().map(|(entity, pos, _)| (pos.clone(), entity)).collect();
+
+        // positions are only borrowed mutably when they change, any mutable access gets replicated
+        for (entity, trans, inputs) in (&entities, &mut trans, (&mut inputs).maybe()).join() {
+            let Some (current) = pos.get(entity).cloned() else {
+                continue;
+            };
+            let target = current.translated(trans);
+            let moving = !trans.is_zero();
+
+            // reset translate component after update
+            *trans = Translate::default();
+
+            // bumping into something hostile attacks it, which costs more or less than the move that was paid for
+            let victim = occupied.get(&target)
+                .filter(|victim| moving && stats.contains(entity) && hostile(&players, entity, **victim))
+                .filter(|victim| health.get(**victim).is_some_and(|health| !health.is_down()));
+            if let Some (victim) = victim {
+                _ = attacks.insert(entity, WantsToAttack { target: *victim, ranged: false });
+                if let Some (energy) = energy.get_mut(entity) {
+                    energy.0 -= costs.melee - costs.step;
+                }
+            }
+
+            // everyone else hears about the move from replication
+            let moved = moving && dungeon.is_walkable(&target) && !occupied.contains_key(&target);
+            if moved {
+                if blockers.contains(entity) {
+                    occupied.remove(&current);
+                    occupied.insert(target.clone(), entity);
+                }
+                _ = pos.insert(entity, target.clone());
+            }
+
+            // if the move was blocked the client has likely already stepped there, this puts it back
+            let in_flight = inputs.and_then(|inputs| inputs.in_flight.take());
+            if let (Some (seq), Some (player)) = (in_flight, players.get(entity)) {
+                let pos = if moved { target } else { current };
+                tx.push(Message::SendPacketTo(player.0, Packet::InputAck(seq, pos)));
+            }
+        }
+    }
+}
+
+/// Resolves this round's attacks. Each hits with the attacker's accuracy less the target's evasion, and shots lose
+/// some accuracy for every tile they travel. A hit does up to the attacker's melee or ranged damage, which is
+/// handed on to `DamageSystem`. Everyone who can see either side is told how it went.
+pub struct CombatSystem;
 
-                _ = tx.send(Message::SendPacket(Packet::UpdateComponent(id, ServerComponentKind::Translate(trans.clone()))));
+impl CombatSystem {
+    const MIN_HIT_CHANCE: i32 = 5;
+    const MAX_HIT_CHANCE: i32 = 95;
+    const RANGE_PENALTY: i32 = 5;       // per tile
+}
+
+impl<'a> System<'a> for CombatSystem {
+    type SystemData = (
+        Entities<'a>,
+        WriteStorage<'a, WantsToAttack>,
+        WriteStorage<'a, SufferDamage>,
+        ReadStorage<'a, CombatStats>,
+        ReadStorage<'a, Position>,
+        Read<'a, ClientViews>,
+        WriteExpect<'a, GameRng>,
+        ReadExpect<'a, ServerTx>
+    );
+
+    fn run(&mut self, (entities, mut attacks, mut damage, stats, pos, views, mut rng, res): Self::SystemData) {
+        for (entity, attack, attacker) in (&entities, &attacks, &stats).join() {
+            if !entities.is_alive(attack.target) {
+                continue;
+            }
+            let evasion = stats.get(attack.target).map_or(0, |target| target.evasion);
+            let range = match (pos.get(entity), pos.get(attack.target)) {
+                (Some (from), Some (to)) if attack.ranged => distance(from, to),
+                _ => 0
+            };
+            let chance = (attacker.accuracy - evasion - range * Self::RANGE_PENALTY).clamp(Self::MIN_HIT_CHANCE, Self::MAX_HIT_CHANCE);
+            let hit = rng.0.gen_range(0..100) < chance;
+
+            // the report goes out first so clients can say who hit whom before how badly
+            let report = AttackReport { attacker: entity.id(), target: attack.target.id(), ranged: attack.ranged, hit };
+            views.notify(&res, &[entity.id(), attack.target.id()], Packet::Attack(report));
+            if hit {
+                let power = if attack.ranged { attacker.ranged } else { attacker.melee };
+                SufferDamage::inflict(&mut damage, attack.target, rng.0.gen_range(1..=power.max(1)));
+            }
+        }
+        attacks.clear();
+    }
+}
+
+/// Resolves all the damage dealt this round, after defence, and tells clients that can see the victim about it.
+/// Monsters brought to 0 health are despawned, players are downed and can't act any more.
+pub struct DamageSystem;
+
+impl<'a> System<'a> for DamageSystem {
+    type SystemData = (
+        Entities<'a>,
+        WriteStorage<'a, SufferDamage>,
+        WriteStorage<'a, Health>,
+        ReadStorage<'a, Defence>,
+        ReadStorage<'a, PlayerDetails>,
+        Read<'a, ClientViews>,
+        ReadExpect<'a, ServerTx>
+    );
+
+    fn run(&mut self, (entities, mut damage, mut health, defence, players, views, res): Self::SystemData) {
+        for (entity, damage) in (&entities, &damage).join() {
+            let Some (current) = health.get(entity).filter(|health| !health.is_down()).map(|health| health.current) else {
+                continue;
+            };
+            let reduction = defence.get(entity).map_or(0, |defence| defence.0);
+            let dealt: i32 = damage.amounts.iter().map(|amount| (amount - reduction).max(0)).sum();
+            views.notify(&res, &[entity.id()], Packet::Damaged(entity.id(), dealt));
+            if dealt == 0 {
+                continue;
+            }
+
+            let remaining = (current - dealt).max(0);
+            if let Some (health) = health.get_mut(entity) {
+                health.current = remaining;
+            }
+            if remaining == 0 {
+                views.notify(&res, &[entity.id()], Packet::Died(entity.id()));
+                if players.contains(entity) {
+                    log::info!("Entity {} has been downed", entity.id());
+                } else {
+                    _ = entities.delete(entity);
+                }
+            }
+        }
+        damage.clear();
+    }
+}
+
+/// Works out what each entity can see after it has moved, and tells players what's in view,
+/// both to them and to the rest of their party. What they can see also decides which entities
+/// get replicated to them, see `ReplicationSystem`.
+pub struct VisibilitySystem;
+
+impl<'a> System<'a> for VisibilitySystem {
+    type SystemData = (
+        Entities<'a>,
+        WriteStorage<'a, Viewshed>,
+        WriteStorage<'a, Explored>,
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, PlayerDetails>,
+        ReadStorage<'a, PartyMember>,
+        ReadExpect<'a, Dungeon>,
+        ReadExpect<'a, ServerTx>
+    );
+
+    fn run(&mut self, (entities, mut viewsheds, mut explored, pos, players, members, dungeon, res): Self::SystemData) {
+        let mut changed = HashSet::new();
+        for (entity, viewshed, pos) in (&entities, &mut viewsheds, &pos).join() {
+            if viewshed.origin.as_ref() == Some (pos) {
+                continue;
+            }
+            viewshed.visible = field_of_view(dungeon.floor(pos.floor), pos, viewshed.range);
+            viewshed.origin = Some (pos.clone());
+            changed.insert(entity);
+        }
+
+        // when anyone in the party sees something new, so does everyone else in it
+        let party_changed = changed.iter().any(|entity| members.contains(*entity));
+        for (entity, viewshed, pos, player) in (&entities, &viewsheds, &pos, &players).join() {
+            if !(changed.contains(&entity) || party_changed && members.contains(entity)) {
+                continue;
+            }
+            let shared_tiles = shared_vision(entity, &viewsheds, &members);
+            if let Some (explored) = explored.get_mut(entity) {
+                explored.0.extend(viewshed.visible.iter().chain(&shared_tiles).cloned());
             }
+            let own = viewshed.visible.iter().map(|tile| (tile.x, tile.y)).collect();
+            let shared = shared_tiles.iter().map(|tile| (tile.x, tile.y)).collect();
+            res.0.push(Message::SendPacketTo(player.0, Packet::Visibility(pos.floor, own, shared)));
         }
     }
-}
\ No newline at end of file
+}
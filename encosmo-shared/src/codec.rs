@@ -0,0 +1,179 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::Packet;
+
+/// Number of bytes used for the big-endian length prefix of each frame.
+pub const LEN_PREFIX_SIZE: usize = 4;
+
+/// Largest payload a single frame may carry. Anything bigger is treated as a
+/// protocol violation since the stream can no longer be trusted after it.
+pub const MAX_FRAME_LEN: usize = 1024 * 1024;
+
+/// How packets are serialized inside a frame.
+///
+/// Every connection starts out speaking `Json`. Either side may switch the format of the frames
+/// *it sends* by sending `Packet::SetWireFormat`, which is itself encoded in the old format; every
+/// frame after it uses the new one. The server answers a client's request with the same packet,
+/// so both directions end up agreeing. Clients only ask for `Binary` once the server has advertised
+/// `Capabilities::BINARY_WIRE_FORMAT` in its `Welcome`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WireFormat {
+    /// human-readable, handy when inspecting traffic
+    #[default]
+    Json,
+    /// compact encoding for normal play
+    Binary
+}
+
+#[derive(Debug)]
+pub enum CodecError {
+    FrameTooLarge (usize),
+    Json (serde_json::Error),
+    Binary (bincode::Error)
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::FrameTooLarge(len) => write!(f, "frame of {} bytes exceeds the maximum of {} bytes", len, MAX_FRAME_LEN),
+            CodecError::Json(e) => write!(f, "json codec error: {}", e),
+            CodecError::Binary(e) => write!(f, "binary codec error: {}", e)
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+impl From<serde_json::Error> for CodecError {
+    fn from(e: serde_json::Error) -> Self {
+        CodecError::Json(e)
+    }
+}
+
+impl From<bincode::Error> for CodecError {
+    fn from(e: bincode::Error) -> Self {
+        CodecError::Binary(e)
+    }
+}
+
+/// Serializes a packet and wraps it in a length-prefixed frame, ready to be written to the stream.
+pub fn encode_packet(packet: &Packet, format: WireFormat) -> Result<Vec<u8>, CodecError> {
+    let payload = match format {
+        WireFormat::Json => serde_json::to_vec(packet)?,
+        WireFormat::Binary => bincode::serialize(packet)?
+    };
+    encode_frame(&payload)
+}
+
+/// Deserializes a packet from the payload of a single frame (without its length prefix).
+pub fn decode_packet(frame: &[u8], format: WireFormat) -> Result<Packet, CodecError> {
+    match format {
+        WireFormat::Json => Ok (serde_json::from_slice(frame)?),
+        WireFormat::Binary => Ok (bincode::deserialize(frame)?)
+    }
+}
+
+pub fn encode_frame(payload: &[u8]) -> Result<Vec<u8>, CodecError> {
+    if payload.len() > MAX_FRAME_LEN {
+        return Err (CodecError::FrameTooLarge(payload.len()));
+    }
+    let mut bytes = Vec::with_capacity(LEN_PREFIX_SIZE + payload.len());
+    bytes.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(payload);
+    Ok (bytes)
+}
+
+/// Reassembles frames from a byte stream.
+///
+/// TCP gives no guarantee that one `read()` lines up with one `write()` on the other end,
+/// so bytes are buffered here until a whole frame is available. Several frames may come out
+/// of a single read, and a frame may span several reads.
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    buf: Vec<u8>
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends freshly read bytes to the internal buffer.
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Pops the next complete frame payload, or `None` if more bytes are needed.
+    pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>, CodecError> {
+        if self.buf.len() < LEN_PREFIX_SIZE {
+            return Ok (None);
+        }
+        let mut prefix = [0u8; LEN_PREFIX_SIZE];
+        prefix.copy_from_slice(&self.buf[..LEN_PREFIX_SIZE]);
+        let len = u32::from_be_bytes(prefix) as usize;
+        if len > MAX_FRAME_LEN {
+            return Err (CodecError::FrameTooLarge(len));
+        }
+        if self.buf.len() < LEN_PREFIX_SIZE + len {
+            return Ok (None);
+        }
+        let frame = self.buf[LEN_PREFIX_SIZE..LEN_PREFIX_SIZE + len].to_vec();
+        self.buf.drain(..LEN_PREFIX_SIZE + len);
+        Ok (Some (frame))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server_components::Translate;
+
+    fn frame(payload: &[u8]) -> Vec<u8> {
+        encode_frame(payload).unwrap()
+    }
+
+    #[test]
+    fn two_frames_in_one_read() {
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&[frame(b"first"), frame(b"second")].concat());
+        assert_eq!(decoder.next_frame().unwrap(), Some (b"first".to_vec()));
+        assert_eq!(decoder.next_frame().unwrap(), Some (b"second".to_vec()));
+        assert_eq!(decoder.next_frame().unwrap(), None);
+    }
+
+    #[test]
+    fn frame_split_across_reads() {
+        let bytes = frame(b"split across reads");
+        let mut decoder = FrameDecoder::new();
+        // the first cut is inside the length prefix
+        for chunk in [&bytes[..2], &bytes[2..6], &bytes[6..10]] {
+            decoder.extend(chunk);
+            assert_eq!(decoder.next_frame().unwrap(), None);
+        }
+        decoder.extend(&bytes[10..]);
+        assert_eq!(decoder.next_frame().unwrap(), Some (b"split across reads".to_vec()));
+        assert_eq!(decoder.next_frame().unwrap(), None);
+    }
+
+    #[test]
+    fn frame_too_large() {
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&(MAX_FRAME_LEN as u32 + 1).to_be_bytes());
+        assert!(matches!(decoder.next_frame(), Err (CodecError::FrameTooLarge(len)) if len == MAX_FRAME_LEN + 1));
+        assert!(matches!(encode_frame(&vec![0; MAX_FRAME_LEN + 1]), Err (CodecError::FrameTooLarge(_))));
+    }
+
+    #[test]
+    fn packet_round_trip() {
+        for format in [WireFormat::Json, WireFormat::Binary] {
+            let bytes = encode_packet(&Packet::Move(7, 3, Translate { dx: -1, dy: 1 }), format).unwrap();
+            let mut decoder = FrameDecoder::new();
+            decoder.extend(&bytes);
+            let frame = decoder.next_frame().unwrap().unwrap();
+            let packet = decode_packet(&frame, format).unwrap();
+            assert!(matches!(packet, Packet::Move(7, 3, Translate { dx: -1, dy: 1 })), "{:?} came back as {:?}", format, packet);
+        }
+    }
+}
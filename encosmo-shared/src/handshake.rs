@@ -0,0 +1,33 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// Bump this whenever `Packet` (or anything it carries) changes shape.
+/// Clients and servers only talk to each other if their versions match exactly.
+pub const PROTOCOL_VERSION: u32 = 16;
+
+/// How long either side waits for the other half of the handshake before giving up.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Optional features a peer understands, exchanged in `Hello` and `Welcome`.
+/// The server answers with the intersection of both sides, which is what the connection may use.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    pub const NONE: Capabilities = Capabilities(0);
+    pub const BINARY_WIRE_FORMAT: Capabilities = Capabilities(1 << 0);
+
+    /// Everything this build of the protocol supports.
+    pub fn supported() -> Self {
+        Capabilities::BINARY_WIRE_FORMAT
+    }
+
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersection(self, other: Capabilities) -> Self {
+        Capabilities(self.0 & other.0)
+    }
+}
@@ -1,21 +1,87 @@
+use codec::WireFormat;
+use handshake::Capabilities;
+use map::Map;
 use serde::{Deserialize, Serialize};
-use server_components::ServerComponentKind;
+use server_components::{GameObjectDetails, Item, Position, ServerComponentKind, Translate};
 use uuid::Uuid;
 
 pub mod server_components;
+pub mod codec;
+pub mod handshake;
+pub mod map;
+
+/// Most inputs a client may have sent without hearing back in an `InputAck`. The server drops any past this.
+pub const MAX_UNACKED_INPUTS: usize = 8;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum Packet {
+    // both ways
+    SetWireFormat (WireFormat),     // every frame sent after this one uses the given format
+
     // client-server
+    Hello (u32, Capabilities),      // first packet of every connection: protocol version and what the client supports
     SetName (String),
     Logout,
+    Move (u32, u32, Translate),     // move entity (id) with input (seq), numbered by the client from 0
+    SnapshotAck (u32),      // every snapshot up to and including {seq} has been applied
+    UseLadder (u32, u32),   // entity (id) climbs the ladder it's standing on with input (seq), numbered along with `Move`
+    Wait (u32, u32),        // entity (id) passes its turn with input (seq), numbered along with `Move`
+    Fire (u32, u32, u32),   // entity (id) shoots at entity (target) with input (seq), numbered along with `Move`
+    TravelTo (u32, u32, Position),  // entity (id) walks itself to (position) with input (seq), until told `TravelStopped`
+    Explore (u32, u32),             // entity (id) walks itself to the nearest tile it hasn't seen with input (seq), and so on
+    PickUp (u32, u32),      // entity (id) picks up an item it's standing on with input (seq), numbered along with `Move`
+    Drop (u32, u32, u32),   // entity (id) drops the item (item) it's carrying with input (seq), numbered along with `Move`
+    JoinParty,      // share vision with everyone else in the party
+    LeaveParty,
 
     // server-client
+    Welcome (u32, Capabilities),    // handshake accepted: protocol version and capabilities both sides agreed on
+    Disconnect (String),            // handshake rejected or client kicked, with a human-readable reason
     Id (Uuid),
     PlayerConnected (Uuid),
     PlayerDisconnected (Uuid),
     PlayerEntityId (Uuid, u32),
     Name (Uuid, String),     // player (id) has set their name to (string)
     UpdateComponent (u32, ServerComponentKind),     // update component belonging to entity with id {id}
-    UpsertEntity (u32, Vec<ServerComponentKind>),   // either creates new entity or updates existing entity
+    Snapshot (Snapshot),
+    FloorMap (Map),     // the layout of the floor our player has just arrived on
+    Visibility (u32, Vec<(i32, i32)>, Vec<(i32, i32)>),     // tiles of floor {floor} our player can see, and those only the rest of its party can
+    PartyMembers (Vec<Uuid>),   // everyone in the party, empty if we're not in it
+    Damaged (u32, i32),     // entity (id) took (amount) damage, after its defence
+    Died (u32),             // entity (id) died, or was downed if it's a player
+    Attack (AttackReport),  // for the combat log, sent before the damage it does
+    TravelStopped (TravelStop),     // our player is no longer walking itself anywhere
+    Inventory (Vec<(u32, GameObjectDetails, Item)>),    // every item (id) our player is carrying, sent whenever that changes
+    InputAck (u32, Position),   // every input up to and including {seq} has been processed, leaving our player at {position}
+}
+
+/// Why travelling or exploring came to an end. Any other input also ends it, without saying so.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum TravelStop {
+    Arrived,
+    Explored,           // nothing left on the floor that can be reached and hasn't been seen
+    Blocked,            // something is in the way, or there's no way there
+    Interrupted (u32)   // entity (id) came into view, and looks hostile
+}
+
+/// One attack, as told to everyone who can see the attacker or its target.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AttackReport {
+    pub attacker: u32,
+    pub target: u32,
+    pub ranged: bool,
+    pub hit: bool       // how much damage a hit does is sent separately, as `Packet::Damaged`
+}
+
+/// Replicated world state the server sends every tick something has changed.
+///
+/// A snapshot with a `baseline` is a delta: it only carries the components that changed since that snapshot,
+/// which the client has already applied. One without a `baseline` is the whole world as the client may see it,
+/// and any entity it doesn't mention no longer exists. Clients answer every snapshot with `Packet::SnapshotAck`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Snapshot {
+    pub seq: u32,
+    pub baseline: Option<u32>,
+    pub removed: Vec<u32>,      // applied before `entities`, an id may be removed and reused in the same snapshot
+    pub entities: Vec<(u32, Vec<ServerComponentKind>)>
 }
@@ -0,0 +1,72 @@
+use serde::{Deserialize, Serialize};
+
+use crate::server_components::Position;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Tile {
+    Floor,
+    Wall,
+    Water,      // can be seen across but not walked through
+    DownLadder, // leads to the up ladder of the floor below
+    UpLadder    // leads to the down ladder of the floor above
+}
+
+impl Tile {
+    pub fn is_walkable(self) -> bool {
+        matches!(self, Tile::Floor | Tile::DownLadder | Tile::UpLadder)
+    }
+}
+
+/// One floor of the dungeon, in tile coordinates. Generated by the server and sent to clients as is.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Map {
+    pub floor: u32,
+    pub width: i32,
+    pub height: i32,
+    tiles: Vec<Tile>
+}
+
+impl Map {
+    /// A map of the given size made up entirely of `tile`.
+    pub fn filled(floor: u32, width: i32, height: i32, tile: Tile) -> Self {
+        Map { floor, width, height, tiles: vec![tile; (width * height) as usize] }
+    }
+
+    pub fn in_bounds(&self, x: i32, y: i32) -> bool {
+        x >= 0 && y >= 0 && x < self.width && y < self.height
+    }
+
+    /// Anything outside the map counts as wall so nobody can walk off the edge.
+    pub fn tile(&self, x: i32, y: i32) -> Tile {
+        if self.in_bounds(x, y) {
+            self.tiles[(y * self.width + x) as usize]
+        } else {
+            Tile::Wall
+        }
+    }
+
+    /// Does nothing outside the map, the edge is always wall.
+    pub fn set_tile(&mut self, x: i32, y: i32, tile: Tile) {
+        if self.in_bounds(x, y) {
+            self.tiles[(y * self.width + x) as usize] = tile;
+        }
+    }
+
+    /// Nothing on another floor is walkable, as far as this map is concerned.
+    pub fn is_walkable(&self, pos: &Position) -> bool {
+        pos.floor == self.floor && self.tile(pos.x, pos.y).is_walkable()
+    }
+
+    /// Every walkable tile, row by row.
+    pub fn walkable_tiles(&self) -> impl Iterator<Item = Position> + '_ {
+        self.tiles().filter(|(_, tile)| tile.is_walkable()).map(|(pos, _)| pos)
+    }
+
+    /// Every tile along with its position, row by row.
+    pub fn tiles(&self) -> impl Iterator<Item = (Position, Tile)> + '_ {
+        self.tiles.iter().enumerate().map(|(i, tile)| {
+            let i = i as i32;
+            (Position { x: i % self.width, y: i / self.width, floor: self.floor }, *tile)
+        })
+    }
+}
@@ -15,23 +15,40 @@ use uuid::Uuid;
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum ServerComponentKind {
     Position (Position),
-    Translate (Translate)
+    Translate (Translate),
+    GameObjectDetails (GameObjectDetails),
+    PlayerDetails (PlayerDetails),
+    Health (Health),
+    Defence (Defence),
+    Item (Item)
 }
 
 pub trait UpdatableComponent: Send + Sync + Clone + Component {
     fn update_component(&mut self, new_component: &Self);
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+/// Location of an entity in tiles, on one floor of the dungeon. Floor 0 is the top floor.
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq, Hash)]
 pub struct Position {
     pub x: i32,
-    pub y: i32
+    pub y: i32,
+    pub floor: u32
+}
+
+impl Position {
+    /// The position `t` away from this one, on the same floor.
+    pub fn translated(&self, t: &Translate) -> Position {
+        Position { x: self.x + t.dx, y: self.y + t.dy, floor: self.floor }
+    }
 }
 
+// replicated components are flagged so the server can tell which of them changed since a client last heard.
+// only mutably borrow these when actually changing them, every mutable access counts as a change
 impl Component for Position {
-    type Storage = VecStorage<Self>;
+    type Storage = FlaggedStorage<Self, VecStorage<Self>>;
 }
 
+/// Movement of an entity in tiles. Clients send these as movement intents, a single step at a time.
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct Translate {
     pub dx: i32,
@@ -42,6 +59,17 @@ impl Component for Translate {
     type Storage = VecStorage<Self>;
 }
 
+impl Translate {
+    pub fn is_zero(&self) -> bool {
+        self.dx == 0 && self.dy == 0
+    }
+
+    /// At most one tile in any direction, diagonals included.
+    pub fn is_single_step(&self) -> bool {
+        !self.is_zero() && self.dx.abs() <= 1 && self.dy.abs() <= 1
+    }
+}
+
 impl UpdatableComponent for Translate {
     fn update_component(&mut self, new_component: &Self) {
         *self = new_component.clone();
@@ -51,16 +79,56 @@ impl UpdatableComponent for Translate {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GameObjectDetails {
     pub name: String,
-    pub description: String
+    pub description: String,
+    pub sprite: String      // name of the sprite it's drawn with, from the client's `content/sprites.json`
 }
 
 impl Component for GameObjectDetails {
-    type Storage = VecStorage<Self>;
+    type Storage = FlaggedStorage<Self, VecStorage<Self>>;
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PlayerDetails(pub Uuid);
 
 impl Component for PlayerDetails {
-    type Storage = VecStorage<Self>;
+    type Storage = FlaggedStorage<Self, VecStorage<Self>>;
+}
+
+/// Hit points. Monsters die at 0, players are downed.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Health {
+    pub current: i32,
+    pub max: i32
+}
+
+impl Component for Health {
+    type Storage = FlaggedStorage<Self, VecStorage<Self>>;
+}
+
+impl Health {
+    pub fn new(max: i32) -> Self {
+        Health { current: max, max }
+    }
+
+    pub fn is_down(&self) -> bool {
+        self.current <= 0
+    }
+}
+
+/// Taken off all damage the entity is dealt.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Defence(pub i32);
+
+impl Component for Defence {
+    type Storage = FlaggedStorage<Self, VecStorage<Self>>;
+}
+
+/// Something that can be picked up. Items lying around have a `Position`, carried ones don't, and so can't be seen.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Item {
+    pub count: u32      // how many there are in the pile, e.g. of coins
+}
+
+impl Component for Item {
+    type Storage = FlaggedStorage<Self, VecStorage<Self>>;
 }
\ No newline at end of file